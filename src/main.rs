@@ -1,9 +1,67 @@
+extern crate base64;
+#[cfg(feature = "compression")]
+extern crate bzip2;
+#[cfg(feature = "crypto")]
+extern crate chacha20poly1305;
+#[cfg(feature = "compression")]
+extern crate flate2;
+extern crate rand;
+#[cfg(test)]
+extern crate tempfile;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "pwned")]
+extern crate sha1;
+#[cfg(feature = "toml")]
+extern crate toml;
+#[cfg(feature = "unicode")]
+extern crate unicode_general_category;
+#[cfg(feature = "unicode")]
+extern crate unicode_normalization;
+#[cfg(feature = "unicode")]
+extern crate unicode_width;
+#[cfg(feature = "pwned")]
+extern crate ureq;
+
 pub mod panic;
+pub mod password;
 pub mod result;
+pub mod strings;
+
+use std::env;
+use std::error::Error;
+use std::fmt;
 
 use panic::RawPassword;
 
-fn main() {
+// Used when no password is given on the command line.
+// Try e.g. `cargo run -- 123!` to see what a bad one does.
+const DEFAULT_PASSWORD: &str = "cis198rust!";
+
+// main can return a Result too: if it returns Err, Rust prints
+// "Error: {:?}" of the error to stderr and exits with status 1, without a
+// panic. A String error would come out quoted, so main's errors are
+// MainErrors, whose Debug prints the same message as Display.
+struct MainError(String);
+
+impl fmt::Debug for MainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for MainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for MainError {}
+
+fn main() -> Result<(), Box<dyn Error>> {
     println!("CIS198 Lecture 5: Error Handling in Rust");
 
     // panic::panics();
@@ -20,6 +78,13 @@ fn main() {
     // let raw_pass = RawPassword::new_password("caleb", "123!", 20210225);
     // let raw_pass = RawPassword::new_password("caleb", "1234567", 20210225);
 
-    let raw_pass = RawPassword::new_password("caleb", "1234567!", 20210225);
+    // returns an error from main instead
+    let pass = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_PASSWORD.to_string());
+    let raw_pass = RawPassword::try_new_password("caleb", &pass, 20210225)
+        .map_err(|e| MainError(format!("could not create a password for caleb: {}", e)))?;
     println!("\"Hash\": {}", raw_pass.hash());
+
+    Ok(())
 }
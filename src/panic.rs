@@ -7,6 +7,10 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
+use password::fnv1a;
+#[cfg(feature = "unicode")]
+use strings::normalize_nfc;
+
 pub fn panics() {
     // Recall: ! means a macro
     // panic!("Error description here");
@@ -102,7 +106,8 @@ pub fn capitalize_firstchar(s: &str) -> String {
     Real-life extended example: Password struct
 */
 
-const MIN_PASS_LEN: usize = 5;
+pub const MIN_PASS_LEN: usize = 5;
+pub const PAST_HASH_FILE: &str = "PAST_HASH_FILE";
 
 pub struct RawPassword {
     pub user: String,
@@ -125,17 +130,41 @@ impl RawPassword {
         result
     }
     pub fn hash(&self) -> usize {
-        // Really bad hash function
-        // placeholder for illustration
-        self.user.len() + 3 * self.pass.len() + 7 * self.salt.len()
+        // FNV-1a over all three fields, \0 separated so that moving chars
+        // from one field to the next changes the hash.
+        // Still not a password hash (use argon2 or bcrypt for real), but
+        // unlike legacy_hash it looks at the contents and not just lengths.
+        // The top bit is dropped so the hash fits in an i64: TOML (see
+        // account_to_toml) has no integers above i64::MAX.
+        let record = format!("{}\0{}\0{}", self.user, self.normalized_pass(), self.salt);
+        (fnv1a(record.as_bytes()) & i64::MAX as u64) as usize
+    }
+
+    // Deprecated: the original placeholder hash, which only looks at the
+    // lengths of the fields. Only here to check records stored before
+    // hash() changed (HASH_VERSION 1); don't use it for anything new.
+    pub fn legacy_hash(&self) -> usize {
+        self.user.len() + 3 * self.normalized_pass().len() + 7 * self.salt.len()
+    }
+
+    // With the "unicode" feature, visually identical passwords
+    // (e.g. precomposed vs decomposed "é") hash the same.
+    fn normalized_pass(&self) -> String {
+        #[cfg(feature = "unicode")]
+        let pass = normalize_nfc(&self.pass);
+        #[cfg(not(feature = "unicode"))]
+        let pass = self.pass.clone();
+        pass
     }
     // other functionality
 
     // Validate password
     fn validate_is_good(&self) {
+        // Note: .len() is the length in *bytes*. A 3 emoji password is
+        // 12+ bytes but only 3 characters, so count chars instead.
         if self.pass.is_empty() {
             panic!("Empty password is not secure!");
-        } else if self.pass.len() < MIN_PASS_LEN {
+        } else if self.pass.chars().count() < MIN_PASS_LEN {
             panic!(
                 "Password is too short: must be at least {} chars",
                 MIN_PASS_LEN
@@ -162,7 +191,7 @@ impl RawPassword {
     fn validate_is_not_past_password(&self) {
         // get past pass hashes from a file
         // do some basic file handling
-        let past_hashes = file_to_vec("PAST_HASH_FILE");
+        let past_hashes = file_to_vec(PAST_HASH_FILE);
         for &hash in &past_hashes {
             if self.hash() == hash {
                 panic!("Bad password: same as past hash!");
@@ -0,0 +1,2723 @@
+/*
+    Revisiting the Password example from panic.rs, this time with Result.
+
+    Instead of panicking in eight different places, validation returns a
+    PasswordError describing what went wrong. Now the interface tells the
+    library user that construction can fail:
+
+        pub fn try_new_password(user: &str, pass: &str, salt: usize)
+            -> Result<RawPassword, PasswordError>
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::process;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use panic::{RawPassword, MIN_PASS_LEN, PAST_HASH_FILE};
+use result::HashHistory;
+use strings::{is_anagram, levenshtein};
+
+#[cfg(feature = "crypto")]
+use chacha20poly1305::aead::{Aead, KeyInit};
+#[cfg(feature = "crypto")]
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+#[cfg(feature = "serde")]
+use result::file_to_vec;
+#[cfg(feature = "serde")]
+use serde_json::json;
+#[cfg(feature = "pwned")]
+use sha1::{Digest, Sha1};
+
+// One variant per way that validation can fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordError {
+    ConfirmationMismatch,
+    Empty,
+    TooShort,
+    MissingDigit,
+    MissingSpecial,
+    MissingUppercase,
+    NotEnoughClasses { needed: usize },
+    SameAsUsername,
+    ReversedUsername,
+    AnagramOfUsername,
+    TooSimilarToUsername { min_distance: usize },
+    SameAsSalt,
+    ContainsYear { year: u32 },
+    NonAscii,
+    Blocked,
+    KeyboardPattern,
+    ConsecutiveRepeat,
+    WeakSalt,
+    PastPassword,
+    // The history file existed but could not be read / parsed.
+    // We keep the message rather than the error itself so that
+    // PasswordError can still be Clone and PartialEq.
+    History(String),
+}
+
+impl fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PasswordError::ConfirmationMismatch => write!(f, "Passwords do not match"),
+            PasswordError::Empty => write!(f, "Empty password is not secure!"),
+            PasswordError::TooShort => write!(f, "Password is too short"),
+            PasswordError::MissingDigit => write!(f, "Password must contain a number"),
+            PasswordError::MissingSpecial => {
+                write!(f, "Password must contain a special character")
+            }
+            PasswordError::MissingUppercase => {
+                write!(f, "Password must contain an uppercase letter")
+            }
+            PasswordError::NotEnoughClasses { needed } => write!(
+                f,
+                "Password must use at least {} of: lowercase, uppercase, numbers, special characters",
+                needed
+            ),
+            PasswordError::SameAsUsername => {
+                write!(f, "Password should not be same as username!")
+            }
+            PasswordError::ReversedUsername => {
+                write!(f, "Password should not be the username backwards!")
+            }
+            PasswordError::AnagramOfUsername => {
+                write!(f, "Password should not be an anagram of the username!")
+            }
+            PasswordError::TooSimilarToUsername { min_distance } => write!(
+                f,
+                "Password must differ from the username in at least {} characters",
+                min_distance
+            ),
+            PasswordError::SameAsSalt => write!(f, "Password should not be same as the salt!"),
+            PasswordError::ContainsYear { year } => {
+                write!(f, "Password should not contain the year {}", year)
+            }
+            PasswordError::NonAscii => write!(f, "Password must only contain ASCII characters"),
+            PasswordError::Blocked => write!(f, "Password is on the blocklist"),
+            PasswordError::KeyboardPattern => {
+                write!(f, "Password should not be a keyboard pattern like qwerty")
+            }
+            PasswordError::ConsecutiveRepeat => {
+                write!(f, "Password should not repeat a character twice in a row")
+            }
+            PasswordError::WeakSalt => write!(
+                f,
+                "Salt is too weak: must be at least {} chars",
+                MIN_SALT_LEN
+            ),
+            PasswordError::PastPassword => write!(f, "Bad password: same as past hash!"),
+            PasswordError::History(ref msg) => {
+                write!(f, "Could not read password history: {}", msg)
+            }
+        }
+    }
+}
+
+// Implementing Error lets a PasswordError be returned as a Box<dyn Error>
+impl Error for PasswordError {}
+
+impl PasswordError {
+    // The variant as written in the source, e.g. "TooShort",
+    // handy as a key when counting errors.
+    pub fn variant_name(&self) -> &'static str {
+        match *self {
+            PasswordError::ConfirmationMismatch => "ConfirmationMismatch",
+            PasswordError::Empty => "Empty",
+            PasswordError::TooShort => "TooShort",
+            PasswordError::MissingDigit => "MissingDigit",
+            PasswordError::MissingSpecial => "MissingSpecial",
+            PasswordError::MissingUppercase => "MissingUppercase",
+            PasswordError::NotEnoughClasses { .. } => "NotEnoughClasses",
+            PasswordError::SameAsUsername => "SameAsUsername",
+            PasswordError::ReversedUsername => "ReversedUsername",
+            PasswordError::AnagramOfUsername => "AnagramOfUsername",
+            PasswordError::TooSimilarToUsername { .. } => "TooSimilarToUsername",
+            PasswordError::SameAsSalt => "SameAsSalt",
+            PasswordError::ContainsYear { .. } => "ContainsYear",
+            PasswordError::NonAscii => "NonAscii",
+            PasswordError::Blocked => "Blocked",
+            PasswordError::KeyboardPattern => "KeyboardPattern",
+            PasswordError::ConsecutiveRepeat => "ConsecutiveRepeat",
+            PasswordError::WeakSalt => "WeakSalt",
+            PasswordError::PastPassword => "PastPassword",
+            PasswordError::History(_) => "History",
+        }
+    }
+
+    // HTTP-style status for web frontends:
+    // 409 Conflict for reusing a past password, 500 when our own history
+    // file is broken, and 422 Unprocessable Entity for everything else,
+    // since the request was well formed but the password can't be used.
+    pub fn status_code(&self) -> u16 {
+        match *self {
+            PasswordError::PastPassword => 409,
+            PasswordError::History(_) => 500,
+            _ => 422,
+        }
+    }
+}
+
+// Which rules check_is_good enforces.
+// The default policy is the same set of rules as in panic.rs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub min_len: usize,
+    pub require_digit: bool,
+    pub require_special: bool,
+    pub require_uppercase: bool,
+    // How many of lower / upper / digit / special must appear, 0 for no rule
+    pub min_classes: usize,
+    // Some legacy systems can only store ASCII passwords
+    pub ascii_only: bool,
+    // Organization-specific forbidden passwords, see load_blocklist
+    pub blocklist: HashSet<String>,
+    // Fewest single-char edits (ignoring case) that must separate the
+    // password from the username, 0 for no rule. "caleb1" is 1 from "caleb".
+    pub min_edit_distance_from_user: usize,
+    // Reject any char directly followed by itself, as in "aab"
+    pub no_consecutive_repeats: bool,
+    // Reject passwords containing this year, as in "abc2021!"
+    pub forbidden_year: Option<u32>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_len: MIN_PASS_LEN,
+            require_digit: true,
+            require_special: true,
+            require_uppercase: false,
+            min_classes: 0,
+            ascii_only: false,
+            blocklist: HashSet::new(),
+            min_edit_distance_from_user: 0,
+            no_consecutive_repeats: false,
+            forbidden_year: None,
+        }
+    }
+}
+
+impl RawPassword {
+    pub fn try_new_password(user: &str, pass: &str, salt: usize) -> Result<Self, PasswordError> {
+        Self::try_new_with_policy(user, pass, salt, &PasswordPolicy::default())
+    }
+
+    pub fn try_new_with_policy(
+        user: &str,
+        pass: &str,
+        salt: usize,
+        policy: &PasswordPolicy,
+    ) -> Result<Self, PasswordError> {
+        let result = Self {
+            user: String::from(user),
+            pass: String::from(pass),
+            salt: format!("{}", salt),
+        };
+        result.check_is_good(policy)?;
+        result.check_is_not_past_password()?;
+        Ok(result)
+    }
+
+    // Copy of this password under a different username.
+    // Has to be validated again (with the default policy): the new user
+    // might equal the password.
+    pub fn with_user(&self, new_user: &str) -> Result<Self, PasswordError> {
+        let result = Self {
+            user: String::from(new_user),
+            pass: self.pass.clone(),
+            salt: self.salt.clone(),
+        };
+        result.into_validated()
+    }
+
+    // Copy with surrounding whitespace trimmed from every field, e.g. from
+    // a sloppy copy-paste. Validated again since trimming can make the
+    // password too short.
+    pub fn sanitized(&self) -> Result<Self, PasswordError> {
+        let result = Self {
+            user: self.user.trim().to_string(),
+            pass: self.pass.trim().to_string(),
+            salt: self.salt.trim().to_string(),
+        };
+        result.into_validated()
+    }
+
+    // All of the default checks, on a record that has already been built
+    fn into_validated(self) -> Result<Self, PasswordError> {
+        self.check_is_good(&PasswordPolicy::default())?;
+        self.check_is_not_past_password()?;
+        Ok(self)
+    }
+
+    pub fn is_ascii(&self) -> bool {
+        self.pass.is_ascii()
+    }
+
+    // Every rule in policy that the password breaks, mostly in the same
+    // order as validate_is_good checks them.
+    fn policy_errors(&self, policy: &PasswordPolicy) -> Vec<PasswordError> {
+        let mut errors = Vec::new();
+        // Length is counted in chars, not bytes, as in validate_is_good
+        if self.pass.is_empty() {
+            errors.push(PasswordError::Empty);
+        } else if self.pass.chars().count() < policy.min_len {
+            errors.push(PasswordError::TooShort);
+        }
+        // Salts are stringified numbers, so a password equal to the salt
+        // is all digits and would always fail MissingSpecial first if this
+        // came after the content rules
+        if self.pass == self.salt {
+            errors.push(PasswordError::SameAsSalt);
+        }
+        if policy.require_digit && !self.pass.chars().any(|ch| ch.is_ascii_digit()) {
+            errors.push(PasswordError::MissingDigit);
+        }
+        if policy.require_special && !self.pass.chars().any(|ch| ch.is_ascii_punctuation()) {
+            errors.push(PasswordError::MissingSpecial);
+        }
+        if policy.require_uppercase && !self.pass.chars().any(|ch| ch.is_uppercase()) {
+            errors.push(PasswordError::MissingUppercase);
+        }
+        if self.class_diversity() < policy.min_classes {
+            errors.push(PasswordError::NotEnoughClasses {
+                needed: policy.min_classes,
+            });
+        }
+        if policy.ascii_only && !self.is_ascii() {
+            errors.push(PasswordError::NonAscii);
+        }
+        if is_blocked(&self.pass, &policy.blocklist) {
+            errors.push(PasswordError::Blocked);
+        }
+        if is_keyboard_pattern(&self.pass) {
+            errors.push(PasswordError::KeyboardPattern);
+        }
+        if policy.no_consecutive_repeats && has_consecutive_repeat(&self.pass) {
+            errors.push(PasswordError::ConsecutiveRepeat);
+        }
+        if self.pass == self.user {
+            errors.push(PasswordError::SameAsUsername);
+        }
+        // Checked before the anagram rule, which would also catch this but
+        // with a less helpful message
+        let reversed: String = self.user.chars().rev().collect();
+        if self.pass.to_lowercase() == reversed.to_lowercase() {
+            errors.push(PasswordError::ReversedUsername);
+        }
+        if is_anagram(&self.pass, &self.user) {
+            errors.push(PasswordError::AnagramOfUsername);
+        }
+        let min_distance = policy.min_edit_distance_from_user;
+        if levenshtein(&self.pass.to_lowercase(), &self.user.to_lowercase()) < min_distance {
+            errors.push(PasswordError::TooSimilarToUsername { min_distance });
+        }
+        if let Some(year) = policy.forbidden_year {
+            if contains_year(&self.pass, year) {
+                errors.push(PasswordError::ContainsYear { year });
+            }
+        }
+        if let Err(e) = validate_salt(&self.salt) {
+            errors.push(e);
+        }
+        errors
+    }
+
+    // Same rules as validate_is_good, but returning the first failure
+    fn check_is_good(&self, policy: &PasswordPolicy) -> Result<(), PasswordError> {
+        match self.policy_errors(policy).into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // A missing history file just means there are no past passwords yet.
+    // Any other problem reading it is reported as PasswordError::History.
+    // HashHistory is lazy, so we stop reading at the first match.
+    fn check_is_not_past_password(&self) -> Result<(), PasswordError> {
+        self.check_against_history(PAST_HASH_FILE)
+    }
+
+    fn check_against_history(&self, path: &str) -> Result<(), PasswordError> {
+        let history = match HashHistory::open(path) {
+            Ok(history) => history,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(PasswordError::History(e.to_string())),
+        };
+        // Entries written before HASH_VERSION 2 hold the legacy hash
+        let hash = self.hash();
+        let legacy = self.legacy_hash();
+        for past in history {
+            match past {
+                Ok(past) if past == hash || past == legacy => {
+                    return Err(PasswordError::PastPassword)
+                }
+                Ok(_) => {}
+                Err(e) => return Err(PasswordError::History(e.to_string())),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub const MIN_SALT_LEN: usize = 4;
+
+// The salt is normally a stringified usize, but callers may want to build
+// their own, so check it is at least not trivially short.
+pub fn validate_salt(salt: &str) -> Result<(), PasswordError> {
+    if salt.chars().count() < MIN_SALT_LEN {
+        return Err(PasswordError::WeakSalt);
+    }
+    Ok(())
+}
+
+// Whether the year appears anywhere in the password, as in "abc2021!".
+// People love adding the current year to an old password.
+pub fn contains_year(pass: &str, year: u32) -> bool {
+    pass.contains(&year.to_string())
+}
+
+// All the ways (user, pass, salt) fails the default policy, rather than
+// just the first one. Includes the password history check.
+pub fn validation_errors(user: &str, pass: &str, salt: usize) -> Vec<PasswordError> {
+    let raw = RawPassword {
+        user: String::from(user),
+        pass: String::from(pass),
+        salt: format!("{}", salt),
+    };
+    let mut errors = raw.policy_errors(&PasswordPolicy::default());
+    if let Err(e) = raw.check_is_not_past_password() {
+        errors.push(e);
+    }
+    errors
+}
+
+// Which category a single character of a password falls into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CharClass {
+    Lower,
+    Upper,
+    Digit,
+    Special,
+    // e.g. spaces, emoji, letters without case
+    Other,
+}
+
+impl CharClass {
+    pub fn of(ch: char) -> CharClass {
+        if ch.is_ascii_digit() {
+            CharClass::Digit
+        } else if ch.is_ascii_punctuation() {
+            CharClass::Special
+        } else if ch.is_lowercase() {
+            CharClass::Lower
+        } else if ch.is_uppercase() {
+            CharClass::Upper
+        } else {
+            CharClass::Other
+        }
+    }
+}
+
+// How many characters of each CharClass a password contains
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ClassCounts {
+    pub lower: usize,
+    pub upper: usize,
+    pub digit: usize,
+    pub special: usize,
+    pub other: usize,
+}
+
+impl ClassCounts {
+    pub fn of(pass: &str) -> ClassCounts {
+        let mut counts = ClassCounts::default();
+        for ch in pass.chars() {
+            match CharClass::of(ch) {
+                CharClass::Lower => counts.lower += 1,
+                CharClass::Upper => counts.upper += 1,
+                CharClass::Digit => counts.digit += 1,
+                CharClass::Special => counts.special += 1,
+                CharClass::Other => counts.other += 1,
+            }
+        }
+        counts
+    }
+
+    // How many of lower, upper, digit and special are non-zero
+    pub fn diversity(&self) -> usize {
+        [self.lower, self.upper, self.digit, self.special]
+            .iter()
+            .filter(|&&n| n > 0)
+            .count()
+    }
+}
+
+impl RawPassword {
+    pub fn class_counts(&self) -> ClassCounts {
+        ClassCounts::of(&self.pass)
+    }
+
+    // How many of lower, upper, digit and special appear at least once,
+    // for rules like "use 3 of the 4 kinds of character"
+    pub fn class_diversity(&self) -> usize {
+        self.class_counts().diversity()
+    }
+
+    // Shape of the password with nothing else leaked: one letter per char,
+    // L(ower), U(pper), D(igit), S(pecial) or O(ther). "aB3!" -> "LUDS"
+    pub fn class_pattern(&self) -> String {
+        self.pass
+            .chars()
+            .map(|ch| match CharClass::of(ch) {
+                CharClass::Lower => 'L',
+                CharClass::Upper => 'U',
+                CharClass::Digit => 'D',
+                CharClass::Special => 'S',
+                CharClass::Other => 'O',
+            })
+            .collect()
+    }
+
+    // For "show the last N characters" UIs: everything but the last
+    // reveal chars is replaced by '*'. Works on chars, not bytes.
+    pub fn masked(&self, reveal: usize) -> String {
+        let len = self.pass.chars().count();
+        let hidden = len.saturating_sub(reveal);
+        self.pass
+            .chars()
+            .enumerate()
+            .map(|(i, ch)| if i < hidden { '*' } else { ch })
+            .collect()
+    }
+
+    // For support tickets: first and last char with a fixed "***" in
+    // between, so the length isn't leaked either. "abcdef" -> "a***f".
+    // Passwords of 2 chars or fewer would be entirely revealed that way,
+    // so they are just "***".
+    pub fn mask_middle(&self) -> String {
+        let chars: Vec<char> = self.pass.chars().collect();
+        if chars.len() <= 2 {
+            return String::from("***");
+        }
+        format!("{}***{}", chars[0], chars[chars.len() - 1])
+    }
+
+    // Key stretching, conceptually: hash, then hash the result (in place
+    // of the password), and so on, `rounds` times in total. Every guess
+    // then costs an attacker `rounds` hashes instead of one.
+    // rounds <= 1 is just hash().
+    pub fn hash_rounds(&self, rounds: u32) -> usize {
+        let mut hash = self.hash();
+        for _ in 1..rounds {
+            let next = RawPassword {
+                user: self.user.clone(),
+                pass: hash.to_string(),
+                salt: self.salt.clone(),
+            };
+            hash = next.hash();
+        }
+        hash
+    }
+
+    // Short stable id for a record, for referring to it in logs.
+    // Only the user and salt go into it, never the password.
+    // The \0 separator keeps ("ab", "c") and ("a", "bc") apart.
+    pub fn fingerprint(&self) -> String {
+        let id = format!("{}\0{}", self.user, self.salt);
+        format!("{:08x}", fnv1a(id.as_bytes()) as u32)
+    }
+
+    // Token "salt$hash", base64 encoded, that a stateless service can hand
+    // out and later check a login attempt against with verify_token.
+    pub fn verifier_token(&self) -> String {
+        BASE64.encode(format!("{}${}", self.salt, self.hash()))
+    }
+
+    // Storage format "v<HASH_VERSION>$<salt>$<hash as 16 hex digits>".
+    // The version lets us change the hash algorithm later and still tell
+    // old records apart from new ones.
+    pub fn versioned_hash(&self) -> String {
+        format!("v{}${}${:016x}", HASH_VERSION, self.salt, self.hash())
+    }
+
+    // Bytes needed to store this record: user, salt and the hash as 16 hex
+    // digits (as in versioned_hash). The plaintext is never stored.
+    pub fn storage_bytes(&self) -> usize {
+        self.user.len() + self.salt.len() + format!("{:016x}", self.hash()).len()
+    }
+}
+
+// 1: RawPassword::legacy_hash, 2: the current RawPassword::hash
+pub const HASH_VERSION: u8 = 2;
+
+// Inverse of versioned_hash: (version, salt, hex hash)
+pub fn parse_versioned_hash(s: &str) -> Result<(u8, String, String), String> {
+    let mut parts = s.splitn(3, '$');
+    let version = parts.next().unwrap_or("");
+    let salt = parts.next().ok_or("missing salt")?;
+    let hex = parts.next().ok_or("missing hash")?;
+    if !version.starts_with('v') {
+        return Err(format!("bad version tag: {:?}", version));
+    }
+    let version = version[1..]
+        .parse()
+        .map_err(|e| format!("bad version tag {:?}: {}", version, e))?;
+    if hex.is_empty() || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return Err(format!("hash is not hex: {:?}", hex));
+    }
+    Ok((version, salt.to_string(), hex.to_string()))
+}
+
+// The parts of an account that are safe to hand out: no plaintext password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PublicAccount {
+    pub user: String,
+    pub salt: String,
+    pub hash: usize,
+}
+
+impl<'a> From<&'a RawPassword> for PublicAccount {
+    fn from(raw: &'a RawPassword) -> Self {
+        PublicAccount {
+            user: raw.user.clone(),
+            salt: raw.salt.clone(),
+            hash: raw.hash(),
+        }
+    }
+}
+
+// One {user, pass, salt} object as it arrives over the wire
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct PasswordEntry {
+    user: String,
+    pass: String,
+    salt: usize,
+}
+
+// Parse a JSON array of {user, pass, salt} objects, validate each one,
+// and hand back only the redacted accounts.
+// Two kinds of error can happen here (bad JSON, bad password),
+// hence Box<dyn Error>.
+#[cfg(feature = "serde")]
+pub fn process_json_passwords(json: &str) -> Result<Vec<PublicAccount>, Box<dyn Error>> {
+    let entries: Vec<PasswordEntry> = serde_json::from_str(json)?;
+    let mut accounts = Vec::new();
+    for entry in &entries {
+        let raw = RawPassword::try_new_password(&entry.user, &entry.pass, entry.salt)
+            .map_err(|e| format!("invalid password for user {}: {}", entry.user, e))?;
+        accounts.push(PublicAccount::from(&raw));
+    }
+    Ok(accounts)
+}
+
+// For password change flows: the new password has to be at least
+// min_changes edits away from the old one, so "hunter2!" -> "hunter3!"
+// doesn't count as a new password.
+pub fn is_sufficiently_different(old: &str, new: &str, min_changes: usize) -> bool {
+    levenshtein(old, new) >= min_changes
+}
+
+// Export a hash history file (one integer per line) as a JSON array,
+// e.g. [1,2,3], for other tools to consume.
+#[cfg(feature = "serde")]
+pub fn history_to_json(path: &str) -> Result<String, Box<dyn Error>> {
+    let hashes = file_to_vec(path)?;
+    Ok(serde_json::to_string(&hashes)?)
+}
+
+// A (very) short list of the most common passwords.
+// A real system would load a much bigger list from a file.
+pub const COMMON_PASSWORDS: &[&str] = &[
+    "123456",
+    "password",
+    "123456789",
+    "12345678",
+    "12345",
+    "qwerty",
+    "abc123",
+    "football",
+    "monkey",
+    "letmein",
+    "111111",
+    "iloveyou",
+    "admin",
+    "welcome",
+    "dragon",
+    "sunshine",
+];
+
+// The common password nearest to pass and its edit distance, so callers can
+// warn about near-misses like "passw0rd" and not just exact matches.
+pub fn closest_common_password(pass: &str) -> Option<(String, usize)> {
+    COMMON_PASSWORDS
+        .iter()
+        .map(|common| (common.to_string(), levenshtein(pass, common)))
+        .min_by_key(|&(_, distance)| distance)
+}
+
+// Quote a CSV field if it contains a comma, quote, or newline.
+// Embedded quotes are doubled, as in RFC 4180.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// Write accounts as user,salt,hash rows under a header.
+// Only PublicAccount can be written, so plaintext never ends up on disk.
+pub fn write_accounts_csv(accounts: &[PublicAccount], path: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(path)?;
+    writeln!(file, "user,salt,hash")?;
+    for account in accounts {
+        writeln!(
+            file,
+            "{},{},{}",
+            csv_field(&account.user),
+            csv_field(&account.salt),
+            account.hash
+        )?;
+    }
+    Ok(())
+}
+
+// One (user, pass, salt) entry from a password file
+pub type PasswordRecord = (String, String, usize);
+
+// Password files have one user:pass:salt entry per line, where the
+// delimiter doesn't have to be ':' (tab- or comma-separated works too).
+// Blank lines are skipped; anything else malformed is an error naming the
+// line number. A field can't contain the delimiter: that line would have
+// too many fields.
+pub fn parse_password_file(
+    path: &str,
+    delimiter: char,
+) -> Result<Vec<PasswordRecord>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(delimiter).collect();
+        if fields.len() != 3 {
+            return Err(format!(
+                "line {}: expected user, pass, salt separated by {:?}",
+                i + 1,
+                delimiter
+            )
+            .into());
+        }
+        let salt = fields[2]
+            .parse()
+            .map_err(|e| format!("line {}: bad salt: {}", i + 1, e))?;
+        entries.push((fields[0].to_string(), fields[1].to_string(), salt));
+    }
+    Ok(entries)
+}
+
+// Validate every .txt password file in dir.
+// Maps each file name to the errors of the entries that failed
+// (an empty Vec means every entry in that file was fine).
+pub fn validate_dir(dir: &str) -> Result<HashMap<String, Vec<PasswordError>>, Box<dyn Error>> {
+    let mut results = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("txt") {
+            continue;
+        }
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let path_str = path.to_str().ok_or("non UTF-8 path")?;
+        let errors = parse_password_file(path_str, ':')?
+            .iter()
+            .filter_map(|&(ref user, ref pass, salt)| {
+                RawPassword::try_new_password(user, pass, salt).err()
+            })
+            .collect();
+        results.insert(name, errors);
+    }
+    Ok(results)
+}
+
+// Worker loop for validating passwords on another thread.
+// Runs until every Sender for rx has been dropped, or until nobody is
+// listening on tx any more.
+pub fn validate_worker(
+    rx: Receiver<PasswordRecord>,
+    tx: Sender<Result<PublicAccount, PasswordError>>,
+) {
+    for (user, pass, salt) in rx {
+        let result =
+            RawPassword::try_new_password(&user, &pass, salt).map(|raw| PublicAccount::from(&raw));
+        if tx.send(result).is_err() {
+            break;
+        }
+    }
+}
+
+// (valid_count, total_count) for a user:pass:salt file.
+// Keeps going past invalid passwords; only a malformed file is an error.
+pub fn count_valid_passwords(path: &str) -> Result<(usize, usize), Box<dyn Error>> {
+    let entries = parse_password_file(path, ':')?;
+    let valid = entries
+        .iter()
+        .filter(|&&(ref user, ref pass, salt)| {
+            RawPassword::try_new_password(user, pass, salt).is_ok()
+        })
+        .count();
+    Ok((valid, entries.len()))
+}
+
+// A named, caller-supplied rule for validate_custom
+pub type CustomRule<'a> = (&'a str, fn(&str) -> bool);
+
+// Ad-hoc rules supplied by the caller as (name, predicate) pairs.
+// Rules are checked in order; the name of the first one that fails is
+// the error.
+pub fn validate_custom(pass: &str, rules: &[CustomRule]) -> Result<(), String> {
+    for &(name, rule) in rules {
+        if !rule(pass) {
+            return Err(name.to_string());
+        }
+    }
+    Ok(())
+}
+
+// Validation feedback for web frontends, e.g.
+//     {"valid":false,"errors":["Password is too short"]}
+#[cfg(feature = "serde")]
+pub fn validate_json(user: &str, pass: &str, salt: usize) -> String {
+    let errors: Vec<String> = validation_errors(user, pass, salt)
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+    json!({
+        "valid": errors.is_empty(),
+        "errors": errors,
+    })
+    .to_string()
+}
+
+// Rough brute-force entropy: length * log2(size of the character pool),
+// where the pool is the union of every class the password uses.
+pub fn entropy_bits(pass: &str) -> f64 {
+    let counts = ClassCounts::of(pass);
+    let mut pool = 0;
+    if counts.lower > 0 {
+        pool += 26;
+    }
+    if counts.upper > 0 {
+        pool += 26;
+    }
+    if counts.digit > 0 {
+        pool += 10;
+    }
+    if counts.special > 0 {
+        pool += 32;
+    }
+    if counts.other > 0 {
+        // No good way to size this; count it like another alphabet
+        pool += 26;
+    }
+    if pool == 0 {
+        return 0.0;
+    }
+    pass.chars().count() as f64 * f64::from(pool).log2()
+}
+
+// Assumed attacker speed: ten billion guesses per second, roughly an
+// offline attack on a fast hash with a single modern GPU.
+pub const GUESSES_PER_SECOND: f64 = 1e10;
+
+impl RawPassword {
+    // Expected time to brute force the password at GUESSES_PER_SECOND.
+    // On average the attacker searches half the space before a hit.
+    pub fn estimated_crack_time(&self) -> Duration {
+        let guesses = 2f64.powf(entropy_bits(&self.pass)) / 2.0;
+        let secs = guesses / GUESSES_PER_SECOND;
+        if secs >= u64::MAX as f64 {
+            Duration::MAX
+        } else {
+            Duration::from_secs_f64(secs)
+        }
+    }
+}
+
+// A blocklist file has one forbidden password per line.
+// Blank lines and lines starting with # are skipped.
+pub fn load_blocklist(path: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut blocklist = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            blocklist.insert(line.to_string());
+        }
+    }
+    Ok(blocklist)
+}
+
+pub fn is_blocked(pass: &str, blocklist: &HashSet<String>) -> bool {
+    blocklist.contains(pass)
+}
+
+// Checklist for UIs that tick off requirements as the user types:
+// each active rule of the policy and whether pass satisfies it yet.
+// Rules involving the username are left out since there isn't one yet.
+pub fn rules_status(pass: &str, policy: &PasswordPolicy) -> Vec<(String, bool)> {
+    checklist(policy, &PasswordFacts::of(pass, policy))
+}
+
+// Everything the checklist needs to know about a password. rules_status
+// works these out from the whole password, while PasswordChecker keeps
+// them up to date one char at a time; both then go through checklist, so
+// they always list the same rules.
+#[derive(Debug, Default, Clone, Copy)]
+struct PasswordFacts {
+    len: usize,
+    counts: ClassCounts,
+    ascii: bool,
+    repeated: bool,
+    blocked: bool,
+    keyboard_pattern: bool,
+    has_year: bool,
+}
+
+impl PasswordFacts {
+    fn of(pass: &str, policy: &PasswordPolicy) -> Self {
+        PasswordFacts {
+            len: pass.chars().count(),
+            counts: ClassCounts::of(pass),
+            ascii: pass.is_ascii(),
+            repeated: has_consecutive_repeat(pass),
+            blocked: is_blocked(pass, &policy.blocklist),
+            keyboard_pattern: is_keyboard_pattern(pass),
+            has_year: policy
+                .forbidden_year
+                .is_some_and(|year| contains_year(pass, year)),
+        }
+    }
+}
+
+// One rule of a policy: its name, whether a password with the given facts
+// satisfies it, and which errors mean it was broken. satisfied is None for
+// the rules that need the username, salt or history, which the facts
+// don't cover.
+struct Rule {
+    name: String,
+    satisfied: Option<bool>,
+    broken_by: fn(&PasswordError) -> bool,
+}
+
+// Every rule the policy enforces, in the order the checklists show them
+fn policy_rules(policy: &PasswordPolicy, facts: &PasswordFacts) -> Vec<Rule> {
+    let rule = |name: String, satisfied, broken_by| Rule {
+        name,
+        satisfied,
+        broken_by,
+    };
+    let mut rules = vec![rule(
+        format!("at least {} characters", policy.min_len),
+        Some(facts.len > 0 && facts.len >= policy.min_len),
+        |e| matches!(e, PasswordError::Empty | PasswordError::TooShort),
+    )];
+    if policy.require_digit {
+        rules.push(rule(
+            "contains a number".to_string(),
+            Some(facts.counts.digit > 0),
+            |e| matches!(e, PasswordError::MissingDigit),
+        ));
+    }
+    if policy.require_special {
+        rules.push(rule(
+            "contains a special character".to_string(),
+            Some(facts.counts.special > 0),
+            |e| matches!(e, PasswordError::MissingSpecial),
+        ));
+    }
+    if policy.require_uppercase {
+        rules.push(rule(
+            "contains an uppercase letter".to_string(),
+            Some(facts.counts.upper > 0),
+            |e| matches!(e, PasswordError::MissingUppercase),
+        ));
+    }
+    if policy.min_classes > 0 {
+        rules.push(rule(
+            format!("uses at least {} kinds of character", policy.min_classes),
+            Some(facts.counts.diversity() >= policy.min_classes),
+            |e| matches!(e, PasswordError::NotEnoughClasses { .. }),
+        ));
+    }
+    if policy.ascii_only {
+        rules.push(rule(
+            "only ASCII characters".to_string(),
+            Some(facts.ascii),
+            |e| matches!(e, PasswordError::NonAscii),
+        ));
+    }
+    if policy.no_consecutive_repeats {
+        rules.push(rule(
+            "no character twice in a row".to_string(),
+            Some(!facts.repeated),
+            |e| matches!(e, PasswordError::ConsecutiveRepeat),
+        ));
+    }
+    if !policy.blocklist.is_empty() {
+        rules.push(rule(
+            "not on the blocklist".to_string(),
+            Some(!facts.blocked),
+            |e| matches!(e, PasswordError::Blocked),
+        ));
+    }
+    if let Some(year) = policy.forbidden_year {
+        rules.push(rule(
+            format!("does not contain {}", year),
+            Some(!facts.has_year),
+            |e| matches!(e, PasswordError::ContainsYear { .. }),
+        ));
+    }
+    // Not a policy flag: validation always rejects keyboard walks
+    rules.push(rule(
+        "not a keyboard pattern".to_string(),
+        Some(!facts.keyboard_pattern),
+        |e| matches!(e, PasswordError::KeyboardPattern),
+    ));
+    rules.push(rule("not based on the username".to_string(), None, |e| {
+        matches!(
+            e,
+            PasswordError::SameAsUsername
+                | PasswordError::ReversedUsername
+                | PasswordError::AnagramOfUsername
+                | PasswordError::TooSimilarToUsername { .. }
+        )
+    }));
+    rules.push(rule("different from the salt".to_string(), None, |e| {
+        matches!(e, PasswordError::SameAsSalt)
+    }));
+    rules.push(rule("salt is long enough".to_string(), None, |e| {
+        matches!(e, PasswordError::WeakSalt)
+    }));
+    rules.push(rule("not a past password".to_string(), None, |e| {
+        matches!(e, PasswordError::PastPassword | PasswordError::History(_))
+    }));
+    rules
+}
+
+fn checklist(policy: &PasswordPolicy, facts: &PasswordFacts) -> Vec<(String, bool)> {
+    policy_rules(policy, facts)
+        .into_iter()
+        .filter_map(|rule| rule.satisfied.map(|ok| (rule.name, ok)))
+        .collect()
+}
+
+// Going back the other way from Box<dyn Error>: if the boxed error really
+// is a PasswordError we get it back by value, otherwise the box is
+// returned untouched so the caller can try another type.
+pub fn downcast_password_error(e: Box<dyn Error>) -> Result<PasswordError, Box<dyn Error>> {
+    e.downcast::<PasswordError>().map(|e| *e)
+}
+
+// Registration forms ask for the password twice. A typo in either one
+// is reported before any of the other rules.
+pub fn validate_with_confirmation(
+    pass: &str,
+    confirm: &str,
+    salt: usize,
+    user: &str,
+) -> Result<RawPassword, PasswordError> {
+    if pass != confirm {
+        return Err(PasswordError::ConfirmationMismatch);
+    }
+    RawPassword::try_new_password(user, pass, salt)
+}
+
+// user, salt and hash as a TOML document, e.g. for a config-style export
+#[cfg(feature = "toml")]
+pub fn account_to_toml(acc: &PublicAccount) -> Result<String, Box<dyn Error>> {
+    Ok(toml::to_string(acc)?)
+}
+
+// Values in an audit record are separated by spaces and introduced by
+// key=, so a value containing either (or a quote, or a newline) is put in
+// double quotes with backslash escapes. Plain values are left alone.
+pub fn escape_audit_value(s: &str) -> String {
+    let needs_quotes = s.is_empty()
+        || s.chars()
+            .any(|ch| ch == ' ' || ch == '=' || ch == '"' || ch.is_control() || ch == '\\');
+    if !needs_quotes {
+        return s.to_string();
+    }
+    let mut escaped = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+impl RawPassword {
+    // One line for the audit log, e.g.
+    //     user=caleb salt=20210225 hash=5452184540560906914
+    // The plaintext password is never part of it.
+    pub fn audit_record(&self) -> String {
+        format!(
+            "user={} salt={} hash={}",
+            escape_audit_value(&self.user),
+            escape_audit_value(&self.salt),
+            self.hash()
+        )
+    }
+
+    // audit_record with every value in single quotes, so the line can be
+    // pasted into or piped through a shell as is:
+    //     user='o'\''brien' salt='20210225' hash='1164224284466122187'
+    pub fn audit_record_shell(&self) -> String {
+        format!(
+            "user={} salt={} hash={}",
+            shell_quote(&self.user),
+            shell_quote(&self.salt),
+            shell_quote(&self.hash().to_string())
+        )
+    }
+}
+
+// Nothing is special inside single quotes except the quote itself, which
+// has to close the quoted string, be escaped, and reopen it: ' -> '\''
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// Calls on_error once for every rule the password breaks, e.g. to update
+// a form as the user types. Returns true if nothing failed.
+pub fn validate_streaming<F: FnMut(PasswordError)>(
+    user: &str,
+    pass: &str,
+    salt: usize,
+    mut on_error: F,
+) -> bool {
+    let mut ok = true;
+    for e in validation_errors(user, pass, salt) {
+        ok = false;
+        on_error(e);
+    }
+    ok
+}
+
+// pass if it validates, default otherwise.
+// Note the lifetimes: the result borrows from one of the two inputs, so
+// both need the same lifetime 'a, while user can be anything.
+pub fn valid_or<'a>(pass: &'a str, default: &'a str, salt: usize, user: &str) -> &'a str {
+    match RawPassword::try_new_password(user, pass, salt) {
+        Ok(_) => pass,
+        Err(_) => default,
+    }
+}
+
+// An account loaded back from storage: we know its hash but not the
+// plaintext, so all it can do is check a login attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredPassword {
+    pub user: String,
+    pub salt: String,
+    pub hash: usize,
+}
+
+impl StoredPassword {
+    pub fn verify(&self, attempt: &str) -> bool {
+        let candidate = RawPassword {
+            user: self.user.clone(),
+            pass: String::from(attempt),
+            salt: self.salt.clone(),
+        };
+        candidate.matches_hash(self.hash)
+    }
+}
+
+// For each (user, attempt), whether it logs in against accounts. An
+// unknown user is just false. The accounts are indexed by user first, so
+// this is one pass over each list rather than a search per attempt.
+// If a user appears twice in accounts, the first entry is used.
+pub fn verify_logins(accounts: &[StoredPassword], attempts: &[(String, String)]) -> Vec<bool> {
+    let mut by_user = HashMap::new();
+    for account in accounts {
+        by_user.entry(account.user.as_str()).or_insert(account);
+    }
+    attempts
+        .iter()
+        .map(|(user, attempt)| match by_user.get(user.as_str()) {
+            Some(account) => account.verify(attempt),
+            None => false,
+        })
+        .collect()
+}
+
+impl RawPassword {
+    pub fn from_stored(user: &str, salt: &str, hash: usize) -> StoredPassword {
+        StoredPassword {
+            user: String::from(user),
+            salt: String::from(salt),
+            hash,
+        }
+    }
+
+    // Whether this password hashes to a stored hash, compared in constant
+    // time (see constant_time_eq) as for any login check. Hashes stored
+    // before HASH_VERSION 2 are legacy_hash values, so those match too;
+    // both are always compared (| rather than ||) to keep the timing flat.
+    fn matches_hash(&self, hash: usize) -> bool {
+        let stored = hash.to_be_bytes();
+        constant_time_eq(&self.hash().to_be_bytes(), &stored)
+            | constant_time_eq(&self.legacy_hash().to_be_bytes(), &stored)
+    }
+}
+
+#[cfg(feature = "pwned")]
+const PWNED_RANGE_URL: &str = "https://api.pwnedpasswords.com/range/";
+
+// How many times pass shows up in known breaches, according to the
+// Have I Been Pwned range API. Only the first 5 hex chars of the SHA-1
+// hash are sent (k-anonymity): the API answers with every suffix that
+// shares that prefix, and we look for ours locally.
+#[cfg(feature = "pwned")]
+pub fn check_pwned(pass: &str) -> Result<u64, Box<dyn Error>> {
+    let hash: String = Sha1::digest(pass.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    let (prefix, suffix) = hash.split_at(5);
+    let body = ureq::get(&format!("{}{}", PWNED_RANGE_URL, prefix))
+        .call()?
+        .into_string()?;
+    parse_pwned_range(&body, suffix)
+}
+
+// The range API answers with one SUFFIX:COUNT line per hash.
+// A suffix that isn't listed has never been seen: count 0.
+#[cfg(feature = "pwned")]
+pub fn parse_pwned_range(body: &str, suffix: &str) -> Result<u64, Box<dyn Error>> {
+    for line in body.lines() {
+        let mut parts = line.trim().splitn(2, ':');
+        let line_suffix = parts.next().unwrap_or("");
+        let count = parts
+            .next()
+            .ok_or_else(|| format!("bad range line: {:?}", line))?;
+        if line_suffix.eq_ignore_ascii_case(suffix) {
+            return Ok(count.parse()?);
+        }
+    }
+    Ok(0)
+}
+
+// Data cleaning: split a user:pass:salt file in two. Valid entries are
+// copied to valid_out unchanged; invalid ones go to invalid_out followed
+// by a tab and the reason. Returns (valid_count, invalid_count).
+pub fn partition_passwords(
+    input: &str,
+    valid_out: &str,
+    invalid_out: &str,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    let entries = parse_password_file(input, ':')?;
+    let mut valid = File::create(valid_out)?;
+    let mut invalid = File::create(invalid_out)?;
+    let (mut valid_count, mut invalid_count) = (0, 0);
+    for (user, pass, salt) in entries {
+        match RawPassword::try_new_password(&user, &pass, salt) {
+            Ok(_) => {
+                writeln!(valid, "{}:{}:{}", user, pass, salt)?;
+                valid_count += 1;
+            }
+            Err(e) => {
+                writeln!(invalid, "{}:{}:{}\t{}", user, pass, salt, e)?;
+                invalid_count += 1;
+            }
+        }
+    }
+    Ok((valid_count, invalid_count))
+}
+
+// Strength score from 0 to 100: entropy_bits, capped at 100 bits
+// (which is far beyond anything brute force can reach anyway).
+pub fn strength(pass: &str) -> u8 {
+    entropy_bits(pass).min(100.0).round() as u8
+}
+
+// Positive when new is stronger than old, negative when it is weaker
+pub fn strength_delta(old: &str, new: &str) -> i16 {
+    i16::from(strength(new)) - i16::from(strength(old))
+}
+
+// What changed between two versions of a policy, one line per field,
+// e.g. "min_len: 5 -> 8". Empty if the policies are the same.
+pub fn policy_diff(old: &PasswordPolicy, new: &PasswordPolicy) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.min_len != new.min_len {
+        changes.push(format!("min_len: {} -> {}", old.min_len, new.min_len));
+    }
+    if old.min_classes != new.min_classes {
+        changes.push(format!(
+            "min_classes: {} -> {}",
+            old.min_classes, new.min_classes
+        ));
+    }
+    if old.min_edit_distance_from_user != new.min_edit_distance_from_user {
+        changes.push(format!(
+            "min_edit_distance_from_user: {} -> {}",
+            old.min_edit_distance_from_user, new.min_edit_distance_from_user
+        ));
+    }
+    let flags = [
+        ("require_digit", old.require_digit, new.require_digit),
+        ("require_special", old.require_special, new.require_special),
+        (
+            "require_uppercase",
+            old.require_uppercase,
+            new.require_uppercase,
+        ),
+        ("ascii_only", old.ascii_only, new.ascii_only),
+        (
+            "no_consecutive_repeats",
+            old.no_consecutive_repeats,
+            new.no_consecutive_repeats,
+        ),
+    ];
+    for &(name, old_flag, new_flag) in &flags {
+        if old_flag != new_flag {
+            changes.push(format!("{}: {} -> {}", name, old_flag, new_flag));
+        }
+    }
+    if old.forbidden_year != new.forbidden_year {
+        changes.push(format!(
+            "forbidden_year: {:?} -> {:?}",
+            old.forbidden_year, new.forbidden_year
+        ));
+    }
+    if old.blocklist != new.blocklist {
+        changes.push(format!(
+            "blocklist: {} -> {} entries",
+            old.blocklist.len(),
+            new.blocklist.len()
+        ));
+    }
+    changes
+}
+
+const LOWER: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const UPPER: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &[u8] = b"0123456789";
+const SPECIAL: &[u8] = b"!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~";
+
+// Most entropy generate_with_entropy will aim for: ~150 chars, far more
+// than any password needs.
+pub const MAX_GENERATED_BITS: f64 = 1024.0;
+
+// Random password with entropy_bits of at least min_bits.
+// Characters are picked from each class in turn, so every class shows up
+// once the password is 4 chars long, then the result is shuffled so the
+// classes aren't in a predictable order.
+// min_bits has to be finite and at most MAX_GENERATED_BITS; anything
+// else (e.g. infinity) could never be reached, or not in reasonable time.
+pub fn generate_with_entropy(min_bits: f64) -> Result<String, String> {
+    if !min_bits.is_finite() || min_bits > MAX_GENERATED_BITS {
+        return Err(format!(
+            "min_bits must be a number no bigger than {}, got {}",
+            MAX_GENERATED_BITS, min_bits
+        ));
+    }
+    let mut rng = rand::thread_rng();
+    let classes = [LOWER, UPPER, DIGITS, SPECIAL];
+    let mut chars: Vec<char> = Vec::new();
+    // Same sum as in entropy_bits, kept up to date as classes are added
+    // rather than recomputed from the whole password on every char
+    let mut pool: u32 = 0;
+    while chars.len() as f64 * f64::from(pool).log2().max(0.0) < min_bits {
+        let class = classes[chars.len() % classes.len()];
+        if chars.len() < classes.len() {
+            pool += class.len() as u32;
+        }
+        chars.push(class[rng.gen_range(0..class.len())] as char);
+    }
+    chars.shuffle(&mut rng);
+    Ok(chars.into_iter().collect())
+}
+
+// For dashboards: how many entries failed on each kind of error.
+// Only the first failing rule of each entry is counted.
+pub fn failure_histogram(entries: &[PasswordRecord]) -> HashMap<String, usize> {
+    let mut histogram = HashMap::new();
+    for &(ref user, ref pass, salt) in entries {
+        if let Err(e) = RawPassword::try_new_password(user, pass, salt) {
+            *histogram.entry(e.variant_name().to_string()).or_insert(0) += 1;
+        }
+    }
+    histogram
+}
+
+// Rows of a US keyboard, for spotting "keyboard walks"
+const KEYBOARD_ROWS: &[&str] = &["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+// How many neighbouring keys in a row count as a walk
+const KEYBOARD_RUN: usize = 4;
+
+// Whether the password contains KEYBOARD_RUN or more neighbouring keys
+// from one keyboard row, forwards or backwards: "qwerty1!", "asdf",
+// "12345", "0987" are all caught. Case is ignored.
+pub fn is_keyboard_pattern(pass: &str) -> bool {
+    let pass = pass.to_lowercase();
+    KEYBOARD_ROWS.iter().any(|row| {
+        let reversed: String = row.chars().rev().collect();
+        // Rows are ASCII, so byte windows are whole chars
+        (0..=row.len() - KEYBOARD_RUN).any(|i| {
+            pass.contains(&row[i..i + KEYBOARD_RUN])
+                || pass.contains(&reversed[i..i + KEYBOARD_RUN])
+        })
+    })
+}
+
+// Whether any two neighbouring chars are the same, as in "aab12!"
+pub fn has_consecutive_repeat(pass: &str) -> bool {
+    let chars: Vec<char> = pass.chars().collect();
+    chars.windows(2).any(|pair| pair[0] == pair[1])
+}
+
+// 64-bit FNV-1a: a tiny non-cryptographic hash that, unlike the std
+// DefaultHasher, is guaranteed to give the same answer on every platform
+// and Rust version, so it is safe to store.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+// Compare two byte strings without stopping at the first difference, so
+// the time taken doesn't tell an attacker how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Check a login attempt against a token from RawPassword::verifier_token.
+// A malformed token is an error; a wrong password is just Ok(false).
+pub fn verify_token(token: &str, attempt: &str, user: &str) -> Result<bool, Box<dyn Error>> {
+    let decoded = String::from_utf8(BASE64.decode(token)?)?;
+    let (salt, hash) = decoded
+        .rsplit_once('$')
+        .ok_or("token is missing the $ separator")?;
+    let expected: usize = hash.parse()?;
+    let candidate = RawPassword {
+        user: String::from(user),
+        pass: String::from(attempt),
+        salt: String::from(salt),
+    };
+    Ok(candidate.matches_hash(expected))
+}
+
+// (user, strength) for every entry of a user:pass:salt file, weakest
+// first, so the accounts most in need of a reset come out on top.
+pub fn sort_by_strength(path: &str) -> Result<Vec<(String, u8)>, Box<dyn Error>> {
+    let mut scores: Vec<(String, u8)> = parse_password_file(path, ':')?
+        .into_iter()
+        .map(|(user, pass, _)| {
+            let score = strength(&pass);
+            (user, score)
+        })
+        .collect();
+    scores.sort_by_key(|&(_, score)| score);
+    Ok(scores)
+}
+
+// Fraction (0.0 to 1.0) of the rules in rules_status that pass satisfies,
+// e.g. to fill in a progress bar under a password field.
+pub fn compliance_ratio(pass: &str, policy: &PasswordPolicy) -> f64 {
+    let status = rules_status(pass, policy);
+    if status.is_empty() {
+        return 1.0;
+    }
+    let satisfied = status.iter().filter(|&&(_, ok)| ok).count();
+    satisfied as f64 / status.len() as f64
+}
+
+// Both sides of validation: which rules were satisfied (by name) and
+// which errors were found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationResult {
+    pub passed: Vec<String>,
+    pub failed: Vec<PasswordError>,
+}
+
+// passed names the rules of the default policy, as in the checklists,
+// that none of the errors broke
+pub fn validate_detailed(user: &str, pass: &str, salt: usize) -> ValidationResult {
+    let failed = validation_errors(user, pass, salt);
+    let policy = PasswordPolicy::default();
+    let passed = policy_rules(&policy, &PasswordFacts::of(pass, &policy))
+        .into_iter()
+        .filter(|rule| !failed.iter().any(rule.broken_by))
+        .map(|rule| rule.name)
+        .collect();
+    ValidationResult { passed, failed }
+}
+
+// User and salt that validate_interactive checks every password against
+const INTERACTIVE_USER: &str = "caleb";
+const INTERACTIVE_SALT: usize = 20210225;
+
+// Validate one password per line of input until EOF, writing "OK" or the
+// reason it was rejected to output. Taking any BufRead / Write (rather
+// than stdin / stdout directly) means this can be driven from a String.
+pub fn validate_lines<R: BufRead, W: Write>(input: R, mut output: W) -> Result<(), Box<dyn Error>> {
+    for line in input.lines() {
+        let line = line?;
+        match RawPassword::try_new_password(INTERACTIVE_USER, &line, INTERACTIVE_SALT) {
+            Ok(_) => writeln!(output, "OK")?,
+            Err(e) => writeln!(output, "{}", e)?,
+        }
+    }
+    Ok(())
+}
+
+// Interactive password checker on stdin / stdout
+pub fn validate_interactive() -> Result<(), Box<dyn Error>> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    validate_lines(stdin.lock(), stdout.lock())
+}
+
+// rules_status for a password that is typed one char at a time. Counts
+// are kept as chars arrive, so each keystroke is O(1) instead of
+// rescanning the whole password. Only the blocklist, keyboard pattern and
+// year rules need the whole password, and only when status is asked for.
+pub struct PasswordChecker {
+    policy: PasswordPolicy,
+    pass: String,
+    facts: PasswordFacts,
+    last: Option<char>,
+}
+
+impl PasswordChecker {
+    pub fn new(policy: PasswordPolicy) -> Self {
+        PasswordChecker {
+            policy,
+            pass: String::new(),
+            facts: PasswordFacts {
+                ascii: true,
+                ..PasswordFacts::default()
+            },
+            last: None,
+        }
+    }
+
+    pub fn push(&mut self, ch: char) {
+        self.pass.push(ch);
+        let facts = &mut self.facts;
+        facts.len += 1;
+        match CharClass::of(ch) {
+            CharClass::Lower => facts.counts.lower += 1,
+            CharClass::Upper => facts.counts.upper += 1,
+            CharClass::Digit => facts.counts.digit += 1,
+            CharClass::Special => facts.counts.special += 1,
+            CharClass::Other => facts.counts.other += 1,
+        }
+        facts.ascii = facts.ascii && ch.is_ascii();
+        facts.repeated = facts.repeated || self.last == Some(ch);
+        self.last = Some(ch);
+    }
+
+    pub fn status(&self) -> Vec<(String, bool)> {
+        let facts = PasswordFacts {
+            blocked: is_blocked(&self.pass, &self.policy.blocklist),
+            keyboard_pattern: is_keyboard_pattern(&self.pass),
+            has_year: self
+                .policy
+                .forbidden_year
+                .is_some_and(|year| contains_year(&self.pass, year)),
+            ..self.facts
+        };
+        checklist(&self.policy, &facts)
+    }
+}
+
+// How long the past password check takes for history files of each size.
+// The check scans the whole file (when there is no match), so expect the
+// times to grow linearly with the size. The files are written to the
+// temp dir and deleted again; PAST_HASH_FILE itself is never touched.
+// A size whose file can't be written is left out of the result.
+pub fn bench_validation(history_sizes: &[usize]) -> Vec<(usize, Duration)> {
+    let raw = RawPassword {
+        user: String::from("caleb"),
+        pass: String::from("cis198rust!"),
+        salt: String::from("20210225"),
+    };
+    let (hash, legacy) = (raw.hash(), raw.legacy_hash());
+    let mut timings = Vec::new();
+    for &size in history_sizes {
+        let path = env::temp_dir().join(format!("bench_history_{}_{}", process::id(), size));
+        let path = path.to_string_lossy().into_owned();
+        // Leave out both of raw's hashes so that every line gets read
+        let history: String = (0..)
+            .filter(|&past| past != hash && past != legacy)
+            .take(size)
+            .map(|past| format!("{}\n", past))
+            .collect();
+        if fs::write(&path, history).is_err() {
+            continue;
+        }
+        let start = Instant::now();
+        let _ = raw.check_against_history(&path);
+        timings.push((size, start.elapsed()));
+        let _ = fs::remove_file(&path);
+    }
+    timings
+}
+
+#[cfg(feature = "crypto")]
+const NONCE_LEN: usize = 12;
+
+// Validate (user, pass, salt) with the default policy, then encrypt what
+// we would store for it, "<user>\n<versioned_hash>", with ChaCha20-Poly1305.
+// key must be 32 bytes. A fresh random nonce is used every time and put
+// in front of the ciphertext, since decrypting needs it.
+// The plaintext password is not part of the encrypted record.
+#[cfg(feature = "crypto")]
+pub fn validate_and_encrypt(
+    user: &str,
+    pass: &str,
+    salt: usize,
+    key: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let raw = RawPassword::try_new_password(user, pass, salt)?;
+    let cipher = ChaCha20Poly1305::new_from_slice(key).map_err(|_| "key must be 32 bytes")?;
+    let mut nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce);
+    let record = format!("{}\n{}", raw.user, raw.versioned_hash());
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), record.as_bytes())
+        .map_err(|_| "encryption failed")?;
+    let mut out = nonce.to_vec();
+    out.extend(ciphertext);
+    Ok(out)
+}
+
+// Inverse of validate_and_encrypt. Fails if the key is wrong or the
+// data was tampered with, as well as on records we can't parse.
+#[cfg(feature = "crypto")]
+pub fn decrypt_stored(data: &[u8], key: &[u8]) -> Result<StoredPassword, Box<dyn Error>> {
+    if data.len() < NONCE_LEN {
+        return Err("encrypted record is too short".into());
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new_from_slice(key).map_err(|_| "key must be 32 bytes")?;
+    let record = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "decryption failed: wrong key or corrupted record")?;
+    let record = String::from_utf8(record)?;
+    // versioned_hash never contains a newline, but the user might
+    let (user, versioned) = record.rsplit_once('\n').ok_or("missing hash")?;
+    let (_, salt, hex) = parse_versioned_hash(versioned)?;
+    let hash = usize::from_str_radix(&hex, 16)?;
+    Ok(RawPassword::from_stored(user, &salt, hash))
+}
+
+// Char indices of the "weak" chars in pass, for highlighting in a UI.
+// The heuristic: the first char of each CharClass always adds diversity.
+// After that, a char is weak if it is the same as the one before it
+// ("aa") or one step up or down from it ("ab", "21"), since those are
+// the chars a guesser tries first. E.g. in "passwordd1!" the second 's'
+// and the second 'd' (indices 3 and 8) are weak, and in "abcd1!" the
+// 'b', 'c' and 'd' are.
+pub fn weak_char_positions(pass: &str) -> Vec<usize> {
+    let mut seen = HashSet::new();
+    let mut prev: Option<char> = None;
+    let mut weak = Vec::new();
+    for (i, ch) in pass.chars().enumerate() {
+        let class = CharClass::of(ch);
+        if !seen.insert(class) {
+            if let Some(prev) = prev {
+                let step = (ch as i64 - prev as i64).abs();
+                if step <= 1 {
+                    weak.push(i);
+                }
+            }
+        }
+        prev = Some(ch);
+    }
+    weak
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn raw(user: &str, pass: &str, salt: &str) -> RawPassword {
+        RawPassword {
+            user: String::from(user),
+            pass: String::from(pass),
+            salt: String::from(salt),
+        }
+    }
+
+    // Write contents to a file called name in dir and return its path
+    fn fixture(dir: &TempDir, name: &str, contents: &str) -> String {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn process_json_passwords_redacts_accounts() {
+        let json = r#"[
+            {"user": "caleb", "pass": "cis198rust!", "salt": 20210225},
+            {"user": "alice", "pass": "hunter22!", "salt": 12345678}
+        ]"#;
+        let accounts = process_json_passwords(json).unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].user, "caleb");
+        assert_eq!(
+            accounts[0].hash,
+            raw("caleb", "cis198rust!", "20210225").hash()
+        );
+        assert_eq!(accounts[1].salt, "12345678");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn process_json_passwords_names_offending_user() {
+        let json = r#"[
+            {"user": "caleb", "pass": "cis198rust!", "salt": 20210225},
+            {"user": "bob", "pass": "x", "salt": 20210225}
+        ]"#;
+        let err = process_json_passwords(json).unwrap_err().to_string();
+        assert!(err.contains("bob"), "{}", err);
+    }
+
+    #[test]
+    fn try_new_password_stops_at_first_failure() {
+        assert_eq!(
+            RawPassword::try_new_password("caleb", "", 20210225).err(),
+            Some(PasswordError::Empty)
+        );
+        assert_eq!(
+            RawPassword::try_new_password("caleb", "ab1!", 20210225).err(),
+            Some(PasswordError::TooShort)
+        );
+    }
+
+    #[test]
+    fn with_user_revalidates() {
+        let p = RawPassword::try_new_password("caleb", "cis198rust!", 20210225).unwrap();
+        let copy = p.with_user("alicia").unwrap();
+        assert_eq!(copy.user, "alicia");
+        assert_ne!(copy.hash(), p.hash());
+        assert_eq!(
+            p.with_user("cis198rust!").err(),
+            Some(PasswordError::SameAsUsername)
+        );
+    }
+
+    #[test]
+    fn is_sufficiently_different_counts_edits() {
+        assert!(!is_sufficiently_different("hunter2!", "hunter3!", 3));
+        assert!(is_sufficiently_different("hunter2!", "hunter345", 3));
+        assert!(is_sufficiently_different("abc", "abc", 0));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn history_to_json_is_an_array() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "history", "1\n2\n3\n");
+        assert_eq!(history_to_json(&path).unwrap(), "[1,2,3]");
+        let bad = fixture(&dir, "bad", "1\nnot a hash\n");
+        assert!(history_to_json(&bad).is_err());
+    }
+
+    #[test]
+    fn class_counts_tallies_each_class() {
+        let counts = raw("caleb", "aB3!x", "20210225").class_counts();
+        assert_eq!(counts.lower, 2);
+        assert_eq!(counts.upper, 1);
+        assert_eq!(counts.digit, 1);
+        assert_eq!(counts.special, 1);
+        assert_eq!(counts.other, 0);
+        assert_eq!(raw("caleb", "✓ ", "20210225").class_counts().other, 2);
+    }
+
+    #[test]
+    fn anagram_of_username_is_rejected() {
+        assert_eq!(
+            raw("caleb", "belac1!", "20210225").check_is_good(&PasswordPolicy::default()),
+            Err(PasswordError::AnagramOfUsername)
+        );
+        assert_eq!(
+            raw("caleb", "BlEaC9?", "20210225").check_is_good(&PasswordPolicy::default()),
+            Err(PasswordError::AnagramOfUsername)
+        );
+        assert!(raw("caleb", "cable1!x", "20210225")
+            .check_is_good(&PasswordPolicy::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn closest_common_password_finds_near_match() {
+        assert_eq!(
+            closest_common_password("passw0rd"),
+            Some(("password".to_string(), 1))
+        );
+        assert_eq!(
+            closest_common_password("qwerty"),
+            Some(("qwerty".to_string(), 0))
+        );
+    }
+
+    #[test]
+    fn write_accounts_csv_escapes_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.csv");
+        let path = path.to_str().unwrap();
+        let accounts = [
+            PublicAccount {
+                user: "caleb".to_string(),
+                salt: "20210225".to_string(),
+                hash: 42,
+            },
+            PublicAccount {
+                user: "smith, john".to_string(),
+                salt: "12345678".to_string(),
+                hash: 7,
+            },
+        ];
+        write_accounts_csv(&accounts, path).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "user,salt,hash\ncaleb,20210225,42\n\"smith, john\",12345678,7\n"
+        );
+    }
+
+    #[test]
+    fn validate_dir_maps_files_to_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        fixture(&dir, "good.txt", "caleb:cis198rust!:20210225\n");
+        fixture(
+            &dir,
+            "bad.txt",
+            "caleb:abc:20210225\nalice:nodigits!:12345678\n",
+        );
+        fixture(&dir, "notes.md", "not a password file");
+        let results = validate_dir(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results["good.txt"], []);
+        assert_eq!(
+            results["bad.txt"],
+            [PasswordError::TooShort, PasswordError::MissingDigit]
+        );
+    }
+
+    #[test]
+    fn masked_reveals_last_chars() {
+        let p = raw("caleb", "abc12!", "20210225");
+        assert_eq!(p.masked(2), "****2!");
+        assert_eq!(p.masked(10), "abc12!");
+        assert_eq!(p.masked(0), "******");
+        assert_eq!(raw("caleb", "héllo", "20210225").masked(4), "*éllo");
+    }
+
+    #[test]
+    fn validate_worker_answers_each_entry() {
+        use std::sync::mpsc::channel;
+        use std::thread;
+
+        let (entries_tx, entries_rx) = channel();
+        let (results_tx, results_rx) = channel();
+        let worker = thread::spawn(move || validate_worker(entries_rx, results_tx));
+        entries_tx
+            .send(("caleb".to_string(), "cis198rust!".to_string(), 20210225))
+            .unwrap();
+        entries_tx
+            .send(("alice".to_string(), "abc".to_string(), 20210225))
+            .unwrap();
+        drop(entries_tx);
+        let results: Vec<_> = results_rx.iter().collect();
+        worker.join().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().user, "caleb");
+        assert_eq!(results[1], Err(PasswordError::TooShort));
+    }
+
+    #[test]
+    fn count_valid_passwords_keeps_going() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(
+            &dir,
+            "passwords",
+            "caleb:cis198rust!:20210225\nalice:abc:20210225\nbob:hunter22!:12345678\n",
+        );
+        assert_eq!(count_valid_passwords(&path).unwrap(), (2, 3));
+        let malformed = fixture(&dir, "malformed", "caleb:cis198rust!\n");
+        assert!(count_valid_passwords(&malformed).is_err());
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn both_forms_of_an_accent_hash_the_same() {
+        let precomposed = raw("caleb", "caf\u{e9}19!", "20210225");
+        let decomposed = raw("caleb", "cafe\u{301}19!", "20210225");
+        assert_eq!(precomposed.hash(), decomposed.hash());
+        assert_ne!(
+            precomposed.hash(),
+            raw("caleb", "cafe19!", "20210225").hash()
+        );
+    }
+
+    #[test]
+    fn validate_custom_names_first_failing_rule() {
+        fn long_enough(pass: &str) -> bool {
+            pass.len() >= 4
+        }
+        fn has_x(pass: &str) -> bool {
+            pass.contains('x')
+        }
+        let rules: &[CustomRule] = &[("long enough", long_enough), ("has an x", has_x)];
+        assert_eq!(validate_custom("abcd", rules), Err("has an x".to_string()));
+        assert_eq!(validate_custom("ab", rules), Err("long enough".to_string()));
+        assert_eq!(validate_custom("abcx", rules), Ok(()));
+        assert_eq!(validate_custom("", &[]), Ok(()));
+    }
+
+    #[test]
+    fn versioned_hash_round_trips() {
+        let p = raw("caleb", "cis198rust!", "20210225");
+        let stored = p.versioned_hash();
+        let (version, salt, hex) = parse_versioned_hash(&stored).unwrap();
+        assert_eq!(version, HASH_VERSION);
+        assert_eq!(salt, "20210225");
+        assert_eq!(hex.len(), 16);
+        assert_eq!(usize::from_str_radix(&hex, 16).unwrap(), p.hash());
+
+        assert!(parse_versioned_hash("2$20210225$abc").is_err());
+        assert!(parse_versioned_hash("v2$20210225").is_err());
+        assert!(parse_versioned_hash("v2$20210225$xyz").is_err());
+    }
+
+    #[test]
+    fn ascii_only_policy_rejects_non_ascii() {
+        let p = raw("caleb", "cis198❤rust!", "20210225");
+        assert!(!p.is_ascii());
+        assert!(p.check_is_good(&PasswordPolicy::default()).is_ok());
+        let ascii_only = PasswordPolicy {
+            ascii_only: true,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(p.check_is_good(&ascii_only), Err(PasswordError::NonAscii));
+        assert!(raw("caleb", "cis198rust!", "20210225").is_ascii());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn validate_json_lists_errors() {
+        let feedback: serde_json::Value =
+            serde_json::from_str(&validate_json("caleb", "ab1!", 20210225)).unwrap();
+        assert_eq!(
+            feedback,
+            json!({"valid": false, "errors": ["Password is too short"]})
+        );
+        let feedback: serde_json::Value =
+            serde_json::from_str(&validate_json("caleb", "cis198rust!", 20210225)).unwrap();
+        assert_eq!(feedback, json!({"valid": true, "errors": []}));
+    }
+
+    #[test]
+    fn estimated_crack_time_grows_with_entropy() {
+        let weak = raw("caleb", "abc1!", "20210225").estimated_crack_time();
+        let strong = raw("caleb", "Xq7!mZ#2pL9$wR4&", "20210225").estimated_crack_time();
+        assert!(strong > weak, "{:?} vs {:?}", strong, weak);
+        // Far beyond what a Duration can hold is clamped, not a panic
+        let huge = raw("caleb", &"aB3!".repeat(100), "20210225").estimated_crack_time();
+        assert_eq!(huge, Duration::MAX);
+    }
+
+    #[test]
+    fn blocklist_file_rejects_listed_passwords() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(
+            &dir,
+            "blocklist",
+            "# company list\nacme2020!\n\n  letmein1!  \n",
+        );
+        let blocklist = load_blocklist(&path).unwrap();
+        assert_eq!(blocklist.len(), 2);
+        assert!(is_blocked("letmein1!", &blocklist));
+        assert!(!is_blocked("# company list", &blocklist));
+        let policy = PasswordPolicy {
+            blocklist,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(
+            raw("caleb", "acme2020!", "20210225").check_is_good(&policy),
+            Err(PasswordError::Blocked)
+        );
+        assert!(raw("caleb", "cis198rust!", "20210225")
+            .check_is_good(&policy)
+            .is_ok());
+    }
+
+    #[test]
+    fn rules_status_of_partial_password() {
+        let status = rules_status("ab1", &PasswordPolicy::default());
+        let expected: Vec<(String, bool)> = vec![
+            ("at least 5 characters".to_string(), false),
+            ("contains a number".to_string(), true),
+            ("contains a special character".to_string(), false),
+            ("not a keyboard pattern".to_string(), true),
+        ];
+        assert_eq!(status, expected);
+        assert!(rules_status("ab1!x", &PasswordPolicy::default())
+            .iter()
+            .all(|&(_, ok)| ok));
+    }
+
+    #[test]
+    fn downcast_password_error_recovers_the_variant() {
+        let boxed: Box<dyn Error> = Box::new(PasswordError::TooShort);
+        assert_eq!(
+            downcast_password_error(boxed).ok(),
+            Some(PasswordError::TooShort)
+        );
+        let boxed: Box<dyn Error> = Box::new(io::Error::other("disk on fire"));
+        let back = downcast_password_error(boxed).unwrap_err();
+        assert_eq!(back.to_string(), "disk on fire");
+    }
+
+    #[test]
+    fn length_is_counted_in_chars() {
+        let emoji = raw("caleb", "❤❤❤", "20210225");
+        assert!(emoji.pass.len() >= 9);
+        assert_eq!(
+            emoji.check_is_good(&PasswordPolicy::default()),
+            Err(PasswordError::TooShort)
+        );
+        assert!(raw("caleb", "é1!éé", "20210225")
+            .check_is_good(&PasswordPolicy::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn confirmation_mismatch_comes_first() {
+        assert_eq!(
+            validate_with_confirmation("x", "y", 20210225, "caleb").err(),
+            Some(PasswordError::ConfirmationMismatch)
+        );
+        assert_eq!(
+            validate_with_confirmation("x", "x", 20210225, "caleb").err(),
+            Some(PasswordError::TooShort)
+        );
+        assert!(
+            validate_with_confirmation("cis198rust!", "cis198rust!", 20210225, "caleb").is_ok()
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn account_to_toml_has_each_field() {
+        let acc = PublicAccount {
+            user: "o'brien \"ob\"".to_string(),
+            salt: "20210225".to_string(),
+            hash: 42,
+        };
+        let text = account_to_toml(&acc).unwrap();
+        assert!(text.contains("salt = \"20210225\""), "{}", text);
+        assert!(text.contains("hash = 42"), "{}", text);
+        let back: PublicAccount = toml::from_str(&text).unwrap();
+        assert_eq!(back, acc);
+    }
+
+    #[test]
+    fn audit_record_quotes_awkward_values() {
+        assert_eq!(escape_audit_value("caleb"), "caleb");
+        assert_eq!(escape_audit_value("caleb smith"), "\"caleb smith\"");
+        assert_eq!(escape_audit_value("a=b"), "\"a=b\"");
+        assert_eq!(escape_audit_value("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+        assert_eq!(escape_audit_value(""), "\"\"");
+        let p = raw("caleb smith", "cis198rust!", "20210225");
+        assert_eq!(
+            p.audit_record(),
+            format!("user=\"caleb smith\" salt=20210225 hash={}", p.hash())
+        );
+    }
+
+    #[test]
+    fn validate_streaming_reports_each_failure() {
+        let mut seen = Vec::new();
+        assert!(!validate_streaming("caleb", "abcdef", 20210225, |e| seen.push(e)));
+        assert_eq!(
+            seen,
+            [PasswordError::MissingDigit, PasswordError::MissingSpecial]
+        );
+        let mut calls = 0;
+        assert!(validate_streaming("caleb", "cis198rust!", 20210225, |_| {
+            calls += 1
+        }));
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn parse_password_file_with_tabs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(
+            &dir,
+            "passwords.tsv",
+            "caleb\tcis198rust!\t20210225\n\nalice\thunter22!\t12345678\n",
+        );
+        assert_eq!(
+            parse_password_file(&path, '\t').unwrap(),
+            [
+                ("caleb".to_string(), "cis198rust!".to_string(), 20210225),
+                ("alice".to_string(), "hunter22!".to_string(), 12345678),
+            ]
+        );
+        let bad = fixture(&dir, "bad.tsv", "caleb\tcis\t198\t20210225\n");
+        let err = parse_password_file(&bad, '\t').unwrap_err().to_string();
+        assert!(err.starts_with("line 1:"), "{}", err);
+    }
+
+    #[test]
+    fn valid_or_falls_back_to_default() {
+        assert_eq!(
+            valid_or("abc", "fallback1!", 20210225, "caleb"),
+            "fallback1!"
+        );
+        assert_eq!(
+            valid_or("cis198rust!", "fallback1!", 20210225, "caleb"),
+            "cis198rust!"
+        );
+    }
+
+    #[test]
+    fn short_salts_are_weak() {
+        assert_eq!(validate_salt(""), Err(PasswordError::WeakSalt));
+        assert_eq!(validate_salt("123"), Err(PasswordError::WeakSalt));
+        assert_eq!(validate_salt("1234"), Ok(()));
+        assert_eq!(
+            raw("caleb", "cis198rust!", "").check_is_good(&PasswordPolicy::default()),
+            Err(PasswordError::WeakSalt)
+        );
+        assert_eq!(
+            RawPassword::try_new_password("caleb", "cis198rust!", 7).err(),
+            Some(PasswordError::WeakSalt)
+        );
+    }
+
+    #[cfg(feature = "pwned")]
+    #[test]
+    fn parse_pwned_range_counts() {
+        // What the range API sends back, with the usual \r\n line endings
+        let body = "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n\
+                    1E4C9B93F3F0682250B6CF8331B7EE68FD8:3861493\r\n\
+                    011053FD0102E94D6AE2F8B83D76FAF94F6:13\r\n";
+        assert_eq!(
+            parse_pwned_range(body, "1E4C9B93F3F0682250B6CF8331B7EE68FD8").unwrap(),
+            3861493
+        );
+        assert_eq!(
+            parse_pwned_range(body, "1e4c9b93f3f0682250b6cf8331b7ee68fd8").unwrap(),
+            3861493
+        );
+        assert_eq!(
+            parse_pwned_range(body, "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF").unwrap(),
+            0
+        );
+        assert!(parse_pwned_range("no colon here\r\n", "ABC").is_err());
+        assert!(parse_pwned_range("ABC:lots\r\n", "ABC").is_err());
+    }
+
+    #[test]
+    fn class_pattern_is_one_letter_per_char() {
+        assert_eq!(raw("caleb", "aB3!", "20210225").class_pattern(), "LUDS");
+        assert_eq!(raw("caleb", "é ✓", "20210225").class_pattern(), "LOO");
+    }
+
+    #[test]
+    fn partition_passwords_splits_valid_and_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = fixture(
+            &dir,
+            "input",
+            "caleb:cis198rust!:20210225\nalice:abc:20210225\nbob:hunter22!:12345678\n",
+        );
+        let valid = dir.path().join("valid").to_str().unwrap().to_string();
+        let invalid = dir.path().join("invalid").to_str().unwrap().to_string();
+        assert_eq!(
+            partition_passwords(&input, &valid, &invalid).unwrap(),
+            (2, 1)
+        );
+        assert_eq!(
+            fs::read_to_string(&valid).unwrap(),
+            "caleb:cis198rust!:20210225\nbob:hunter22!:12345678\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&invalid).unwrap(),
+            "alice:abc:20210225\tPassword is too short\n"
+        );
+    }
+
+    #[test]
+    fn strength_delta_is_signed() {
+        assert!(strength_delta("abc1!", "Xq7!mZ#2pL9$") > 0);
+        assert!(strength_delta("Xq7!mZ#2pL9$", "abc1!") < 0);
+        assert_eq!(strength_delta("abc1!", "abc1!"), 0);
+        assert_eq!(strength_delta("", "aB3!"), i16::from(strength("aB3!")));
+    }
+
+    #[test]
+    fn hash_rounds_is_deterministic() {
+        let p = raw("caleb", "cis198rust!", "20210225");
+        assert_eq!(p.hash_rounds(1), p.hash());
+        assert_eq!(p.hash_rounds(0), p.hash());
+        assert_ne!(p.hash_rounds(10), p.hash());
+        assert_eq!(p.hash_rounds(10), p.hash_rounds(10));
+        assert_ne!(p.hash_rounds(10), p.hash_rounds(11));
+    }
+
+    #[test]
+    fn policy_diff_lists_changed_fields() {
+        let old = PasswordPolicy::default();
+        let new = PasswordPolicy {
+            min_len: 8,
+            require_uppercase: true,
+            forbidden_year: Some(2021),
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(
+            policy_diff(&old, &new),
+            [
+                "min_len: 5 -> 8",
+                "require_uppercase: false -> true",
+                "forbidden_year: None -> Some(2021)"
+            ]
+        );
+        assert!(policy_diff(&old, &old.clone()).is_empty());
+    }
+
+    #[test]
+    fn failure_histogram_counts_first_failures() {
+        let entry = |user: &str, pass: &str| (user.to_string(), pass.to_string(), 20210225);
+        let entries = [
+            entry("caleb", "ab1"),
+            entry("alice", "x"),
+            entry("bob", "nodigits!"),
+            entry("carol", "cis198rust!"),
+        ];
+        let histogram = failure_histogram(&entries);
+        assert_eq!(histogram.len(), 2);
+        assert_eq!(histogram["TooShort"], 2);
+        assert_eq!(histogram["MissingDigit"], 1);
+    }
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(PasswordError::TooShort.status_code(), 422);
+        assert_eq!(PasswordError::SameAsUsername.status_code(), 422);
+        assert_eq!(PasswordError::PastPassword.status_code(), 409);
+        assert_eq!(
+            PasswordError::History("disk on fire".to_string()).status_code(),
+            500
+        );
+    }
+
+    #[test]
+    fn sanitized_trims_and_revalidates() {
+        let sloppy = raw(" caleb\t", "  cis198rust!  ", "20210225 ");
+        let clean = sloppy.sanitized().unwrap();
+        assert_eq!(clean.user, "caleb");
+        assert_eq!(clean.pass, "cis198rust!");
+        assert_eq!(clean.salt, "20210225");
+        assert_eq!(clean.hash(), raw("caleb", "cis198rust!", "20210225").hash());
+        assert_eq!(
+            raw("caleb", "  ab1!  ", "20210225").sanitized().err(),
+            Some(PasswordError::TooShort)
+        );
+    }
+
+    #[test]
+    fn fingerprint_ignores_the_password() {
+        let a = raw("caleb", "cis198rust!", "20210225").fingerprint();
+        let b = raw("caleb", "different1!", "20210225").fingerprint();
+        let c = raw("alice", "cis198rust!", "20210225").fingerprint();
+        assert_eq!(a.len(), 8);
+        assert!(a.chars().all(|ch| ch.is_ascii_hexdigit()));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(
+            raw("ab", "x", "c").fingerprint(),
+            raw("a", "x", "bc").fingerprint()
+        );
+    }
+
+    #[test]
+    fn class_diversity_counts_classes_used() {
+        assert_eq!(raw("caleb", "aB3", "20210225").class_diversity(), 3);
+        assert_eq!(raw("caleb", "aaaa", "20210225").class_diversity(), 1);
+        assert_eq!(raw("caleb", "aB3!", "20210225").class_diversity(), 4);
+        // Other chars aren't one of the four classes
+        assert_eq!(raw("caleb", "✓ ", "20210225").class_diversity(), 0);
+    }
+
+    #[test]
+    fn min_classes_policy() {
+        let policy = PasswordPolicy {
+            require_digit: false,
+            require_special: false,
+            min_classes: 3,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(
+            raw("caleb", "lowercaseonly", "20210225").check_is_good(&policy),
+            Err(PasswordError::NotEnoughClasses { needed: 3 })
+        );
+        assert!(raw("caleb", "Lowercase9", "20210225")
+            .check_is_good(&policy)
+            .is_ok());
+    }
+
+    #[test]
+    fn verifier_token_round_trips() {
+        let p = raw("caleb", "cis198rust!", "20210225");
+        let token = p.verifier_token();
+        assert!(verify_token(&token, "cis198rust!", "caleb").unwrap());
+        assert!(!verify_token(&token, "wrong!", "caleb").unwrap());
+        assert!(!verify_token(&token, "cis198rust!", "alicia").unwrap());
+        assert!(verify_token("not base64!", "cis198rust!", "caleb").is_err());
+        let no_separator = BASE64.encode("20210225");
+        assert!(verify_token(&no_separator, "cis198rust!", "caleb").is_err());
+    }
+
+    #[test]
+    fn sort_by_strength_puts_weakest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(
+            &dir,
+            "passwords",
+            "alice:Xq7!mZ#2pL9$:1234\nbob:abc:1234\ncaleb:cis198rust!:20210225\n",
+        );
+        let sorted = sort_by_strength(&path).unwrap();
+        let users: Vec<&str> = sorted.iter().map(|(user, _)| user.as_str()).collect();
+        assert_eq!(users, ["bob", "caleb", "alice"]);
+        assert_eq!(sorted[0].1, strength("abc"));
+    }
+
+    #[test]
+    fn reversed_username_is_rejected() {
+        let errors = validation_errors("caleb", "belac", 20210225);
+        assert_eq!(errors[0], PasswordError::MissingDigit);
+        assert!(errors.contains(&PasswordError::ReversedUsername));
+        // With the content rules out of the way it is the first failure,
+        // ahead of the anagram rule that would also catch it
+        let letters_only = PasswordPolicy {
+            require_digit: false,
+            require_special: false,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(
+            raw("caleb", "BeLaC", "20210225").check_is_good(&letters_only),
+            Err(PasswordError::ReversedUsername)
+        );
+    }
+
+    #[test]
+    fn compliance_ratio_is_the_fraction_passed() {
+        let policy = PasswordPolicy::default();
+        // Passes the digit and keyboard rules, fails length and special
+        assert!((compliance_ratio("ab1", &policy) - 0.5).abs() < 1e-9);
+        assert!((compliance_ratio("cis198rust!", &policy) - 1.0).abs() < 1e-9);
+        // An empty password only passes the keyboard rule
+        assert!((compliance_ratio("", &policy) - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mask_middle_hides_length() {
+        assert_eq!(raw("caleb", "abcdef", "20210225").mask_middle(), "a***f");
+        assert_eq!(raw("caleb", "abc", "20210225").mask_middle(), "a***c");
+        assert_eq!(raw("caleb", "ab", "20210225").mask_middle(), "***");
+        assert_eq!(raw("caleb", "", "20210225").mask_middle(), "***");
+        assert_eq!(raw("caleb", "éxyz❤", "20210225").mask_middle(), "é***❤");
+    }
+
+    #[test]
+    fn validate_detailed_lists_both_sides() {
+        let result = validate_detailed("caleb", "abcdefg", 20210225);
+        assert_eq!(
+            result.failed,
+            [PasswordError::MissingDigit, PasswordError::MissingSpecial]
+        );
+        assert!(result.passed.contains(&"at least 5 characters".to_string()));
+        assert!(result
+            .passed
+            .contains(&"not a keyboard pattern".to_string()));
+        assert!(result
+            .passed
+            .contains(&"not based on the username".to_string()));
+        assert!(!result.passed.contains(&"contains a number".to_string()));
+        assert!(!result
+            .passed
+            .contains(&"contains a special character".to_string()));
+        // A valid password passes every checklist rule, under the same names
+        let valid = validate_detailed("caleb", "cis198rust!", 20210225);
+        for (name, _) in rules_status("cis198rust!", &PasswordPolicy::default()) {
+            assert!(valid.passed.contains(&name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn validate_lines_answers_each_line() {
+        let input: &[u8] = b"cis198rust!\nabc\n";
+        let mut output = Vec::new();
+        validate_lines(input, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "OK\nPassword is too short\n"
+        );
+    }
+
+    #[test]
+    fn storage_bytes_sums_stored_fields() {
+        let p = raw("caleb", "cis198rust!", "20210225");
+        let (_, salt, hex) = parse_versioned_hash(&p.versioned_hash()).unwrap();
+        assert_eq!(p.storage_bytes(), "caleb".len() + salt.len() + hex.len());
+        assert_eq!(p.storage_bytes(), 5 + 8 + 16);
+        // The plaintext doesn't count
+        assert_eq!(
+            raw("caleb", "a much longer password!1", "20210225").storage_bytes(),
+            p.storage_bytes()
+        );
+    }
+
+    #[test]
+    fn forbidden_year_is_rejected() {
+        assert!(contains_year("abc2021!", 2021));
+        assert!(!contains_year("abc2020!", 2021));
+        let policy = PasswordPolicy {
+            forbidden_year: Some(2021),
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(
+            raw("caleb", "abc2021!", "12345").check_is_good(&policy),
+            Err(PasswordError::ContainsYear { year: 2021 })
+        );
+        assert!(raw("caleb", "abc2020!", "12345")
+            .check_is_good(&policy)
+            .is_ok());
+        // The salt's date doesn't forbid a year by itself
+        assert!(raw("caleb", "abc2021!", "20210225")
+            .check_is_good(&PasswordPolicy::default())
+            .is_ok());
+        assert!(rules_status("abc2021!", &policy)
+            .contains(&("does not contain 2021".to_string(), false)));
+    }
+
+    #[test]
+    fn audit_record_shell_quotes_single_quotes() {
+        let p = raw("o'brien", "cis198rust!", "20210225");
+        assert_eq!(
+            p.audit_record_shell(),
+            format!("user='o'\\''brien' salt='20210225' hash='{}'", p.hash())
+        );
+    }
+
+    #[test]
+    fn too_similar_to_username_is_rejected() {
+        let policy = PasswordPolicy {
+            require_special: false,
+            min_edit_distance_from_user: 3,
+            ..PasswordPolicy::default()
+        };
+        // "caleb1" is also an anagram of "caleb" (digits don't count), so
+        // that rule comes first; both are reported
+        let errors = raw("caleb", "caleb1", "20210225").policy_errors(&policy);
+        assert_eq!(
+            errors,
+            [
+                PasswordError::AnagramOfUsername,
+                PasswordError::TooSimilarToUsername { min_distance: 3 }
+            ]
+        );
+        assert_eq!(
+            raw("caleb", "Calebs1", "20210225").check_is_good(&policy),
+            Err(PasswordError::TooSimilarToUsername { min_distance: 3 })
+        );
+        assert!(raw("caleb", "cal1xyz", "20210225")
+            .check_is_good(&policy)
+            .is_ok());
+    }
+
+    #[test]
+    fn consecutive_repeats_policy() {
+        let policy = PasswordPolicy {
+            no_consecutive_repeats: true,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(
+            raw("caleb", "aab12!", "20210225").check_is_good(&policy),
+            Err(PasswordError::ConsecutiveRepeat)
+        );
+        assert!(raw("caleb", "aba12!", "20210225")
+            .check_is_good(&policy)
+            .is_ok());
+        // Off by default
+        assert!(raw("caleb", "aab12!", "20210225")
+            .check_is_good(&PasswordPolicy::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn bench_validation_times_each_size() {
+        let sizes = [0, 10, 1000];
+        let timings = bench_validation(&sizes);
+        let measured: Vec<usize> = timings.iter().map(|&(size, _)| size).collect();
+        assert_eq!(measured, sizes);
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn validate_and_encrypt_round_trips() {
+        let key = [7; 32];
+        let data = validate_and_encrypt("caleb", "cis198rust!", 20210225, &key).unwrap();
+        let stored = decrypt_stored(&data, &key).unwrap();
+        assert_eq!(stored.user, "caleb");
+        assert_eq!(stored.salt, "20210225");
+        assert_eq!(stored.hash, raw("caleb", "cis198rust!", "20210225").hash());
+        assert!(stored.verify("cis198rust!"));
+        // Nothing readable in the ciphertext, and a fresh nonce every time
+        assert!(!data.windows(5).any(|w| w == b"caleb"));
+        assert_ne!(
+            data,
+            validate_and_encrypt("caleb", "cis198rust!", 20210225, &key).unwrap()
+        );
+
+        assert!(decrypt_stored(&data, &[8; 32]).is_err());
+        assert!(decrypt_stored(&data[..5], &key).is_err());
+        assert!(validate_and_encrypt("caleb", "cis198rust!", 20210225, &[7; 16]).is_err());
+        assert!(validate_and_encrypt("caleb", "abc", 20210225, &key).is_err());
+    }
+
+    #[test]
+    fn weak_char_positions_of_mostly_lowercase() {
+        assert_eq!(weak_char_positions("passwordd1!"), [3, 8]);
+        assert_eq!(weak_char_positions("abcd1!"), [1, 2, 3]);
+        assert!(weak_char_positions("aB3!").is_empty());
+        assert!(weak_char_positions("").is_empty());
+    }
+
+    #[test]
+    fn verify_logins_checks_each_attempt() {
+        let caleb = raw("caleb", "cis198rust!", "20210225");
+        let alice = raw("alice", "hunter22!", "12345678");
+        let accounts = [
+            RawPassword::from_stored("caleb", "20210225", caleb.hash()),
+            RawPassword::from_stored("alice", "12345678", alice.hash()),
+        ];
+        let attempt = |user: &str, pass: &str| (user.to_string(), pass.to_string());
+        let attempts = [
+            attempt("caleb", "cis198rust!"),
+            attempt("caleb", "hunter22!"),
+            attempt("alice", "hunter22!"),
+            attempt("mallory", "cis198rust!"),
+        ];
+        assert_eq!(
+            verify_logins(&accounts, &attempts),
+            [true, false, true, false]
+        );
+    }
+
+    #[test]
+    fn stored_password_verifies_attempts() {
+        let original = raw("caleb", "cis198rust!", "20210225");
+        let stored = RawPassword::from_stored("caleb", "20210225", original.hash());
+        assert!(stored.verify("cis198rust!"));
+        assert!(!stored.verify("cis198rust?"));
+        assert!(!stored.verify(""));
+    }
+
+    #[test]
+    fn generate_with_entropy_reaches_threshold() {
+        for &bits in &[0.0, 1.0, 20.0, 64.0, 100.5, MAX_GENERATED_BITS] {
+            let pass = generate_with_entropy(bits).unwrap();
+            assert!(entropy_bits(&pass) >= bits, "{:?} for {} bits", pass, bits);
+        }
+        // No longer than needed: 10 chars from all 94 is 65.5 bits, 9 is 59
+        assert_eq!(generate_with_entropy(64.0).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn generate_with_entropy_rejects_unreachable_targets() {
+        assert!(generate_with_entropy(f64::INFINITY).is_err());
+        assert!(generate_with_entropy(f64::NAN).is_err());
+        assert!(generate_with_entropy(MAX_GENERATED_BITS + 1.0).is_err());
+        assert!(generate_with_entropy(1e300).is_err());
+    }
+
+    #[test]
+    fn password_equal_to_salt_is_rejected() {
+        assert_eq!(
+            RawPassword::try_new_password("caleb", "20210225", 20210225).err(),
+            Some(PasswordError::SameAsSalt)
+        );
+    }
+
+    #[test]
+    fn keyboard_pattern_is_flagged() {
+        assert!(is_keyboard_pattern("qwerty1!"));
+        assert!(is_keyboard_pattern("x0987!"));
+        assert!(!is_keyboard_pattern("qwe1!rty"));
+        assert_eq!(
+            RawPassword::try_new_password("caleb", "qwerty1!", 20210225).err(),
+            Some(PasswordError::KeyboardPattern)
+        );
+    }
+
+    #[test]
+    fn keyboard_pattern_shows_in_checklist() {
+        let policy = PasswordPolicy::default();
+        assert!(RawPassword::try_new_with_policy("caleb", "qwer1!ab", 20210225, &policy).is_err());
+        let keyboard = ("not a keyboard pattern".to_string(), false);
+        assert!(rules_status("qwer1!ab", &policy).contains(&keyboard));
+        assert!(compliance_ratio("qwer1!ab", &policy) < 1.0);
+        let mut checker = PasswordChecker::new(policy);
+        for ch in "qwer1!ab".chars() {
+            checker.push(ch);
+        }
+        assert!(checker.status().contains(&keyboard));
+    }
+
+    #[test]
+    fn password_checker_flips_digit_rule() {
+        let mut checker = PasswordChecker::new(PasswordPolicy::default());
+        let digit_rule = |checker: &PasswordChecker| {
+            checker
+                .status()
+                .into_iter()
+                .find(|(name, _)| name == "contains a number")
+                .map(|(_, ok)| ok)
+        };
+        for ch in "abc".chars() {
+            checker.push(ch);
+            assert_eq!(digit_rule(&checker), Some(false));
+        }
+        checker.push('1');
+        assert_eq!(digit_rule(&checker), Some(true));
+    }
+
+    #[test]
+    fn password_checker_matches_rules_status() {
+        let strict = PasswordPolicy {
+            require_uppercase: true,
+            min_classes: 3,
+            ascii_only: true,
+            no_consecutive_repeats: true,
+            blocklist: ["abé1".to_string()].iter().cloned().collect(),
+            forbidden_year: Some(2021),
+            ..PasswordPolicy::default()
+        };
+        for policy in &[PasswordPolicy::default(), strict] {
+            for pass in &["abé1!X", "qwerty", "aa11!!", "Zz9-", "x2021!"] {
+                let mut checker = PasswordChecker::new(policy.clone());
+                let mut typed = String::new();
+                assert_eq!(checker.status(), rules_status("", policy));
+                for ch in pass.chars() {
+                    checker.push(ch);
+                    typed.push(ch);
+                    assert_eq!(checker.status(), rules_status(&typed, policy));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_hash_is_the_length_formula() {
+        let p = raw("caleb", "cis198rust!", "20210225");
+        assert_eq!(p.legacy_hash(), 5 + 3 * 11 + 7 * 8);
+        assert_ne!(p.hash(), p.legacy_hash());
+    }
+
+    #[test]
+    fn hash_fits_in_i64() {
+        for pass in &["cis198rust!", "hunter2!", "Zz9-Zz9-", "abé1!X"] {
+            let hash = raw("caleb", pass, "20210225").hash();
+            assert!(hash as u64 <= i64::MAX as u64, "{} for {:?}", hash, pass);
+        }
+    }
+
+    #[test]
+    fn history_catches_legacy_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = raw("caleb", "cis198rust!", "20210225");
+        let legacy = fixture(&dir, "legacy", &format!("1\n{}\n", p.legacy_hash()));
+        assert_eq!(
+            p.check_against_history(&legacy),
+            Err(PasswordError::PastPassword)
+        );
+        let current = fixture(&dir, "current", &format!("{}\n", p.hash()));
+        assert_eq!(
+            p.check_against_history(&current),
+            Err(PasswordError::PastPassword)
+        );
+        let other = raw("caleb", "different1!", "20210225");
+        let unrelated = fixture(&dir, "unrelated", &format!("{}\n", other.hash()));
+        assert_eq!(p.check_against_history(&unrelated), Ok(()));
+    }
+
+    #[test]
+    fn legacy_hashes_still_verify() {
+        let p = raw("caleb", "cis198rust!", "20210225");
+        let stored = RawPassword::from_stored("caleb", "20210225", p.legacy_hash());
+        assert!(stored.verify("cis198rust!"));
+        assert!(!stored.verify("cis198rust?x"));
+
+        let token = BASE64.encode(format!("20210225${}", p.legacy_hash()));
+        assert!(verify_token(&token, "cis198rust!", "caleb").unwrap());
+        // legacy_hash only sees lengths, so a wrong guess has to differ in length
+        assert!(!verify_token(&token, "wrong!", "caleb").unwrap());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn account_to_toml_round_trips() {
+        for i in 0..200 {
+            let acc = PublicAccount::from(&raw("caleb", &format!("pass{}!", i), "20210225"));
+            let text = account_to_toml(&acc).unwrap();
+            let back: PublicAccount = toml::from_str(&text).unwrap();
+            assert_eq!(back, acc);
+        }
+    }
+}
@@ -1,9 +1,20 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Lines;
 use std::io::Read;
 use std::io::Result;
+use std::io::{BufWriter, Write};
+use std::io::{Seek, SeekFrom};
+use std::str;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 /*
     Result<T, U>
@@ -89,6 +100,487 @@ pub fn read_whole_file_good(file: &str) -> Result<String> {
     Ok(string)
 }
 
+// A nameable alternative to returning `impl Iterator`:
+// callers can store a LineReader in their own structs.
+// Lines are read lazily, one per call to next().
+pub struct LineReader {
+    lines: Lines<BufReader<File>>,
+}
+
+impl LineReader {
+    pub fn open(path: &str) -> Result<LineReader> {
+        let file = File::open(path)?;
+        Ok(LineReader {
+            lines: BufReader::new(file).lines(),
+        })
+    }
+}
+
+impl Iterator for LineReader {
+    type Item = Result<String>;
+    fn next(&mut self) -> Option<Result<String>> {
+        self.lines.next()
+    }
+}
+
+// Lazy version of file_to_vec: yields each past hash of a history file as
+// it is read, so a caller looking for one hash can stop at the first match
+// without reading (or parsing) the rest of the file.
+pub struct HashHistory {
+    lines: LineReader,
+}
+
+impl HashHistory {
+    pub fn open(path: &str) -> Result<HashHistory> {
+        Ok(HashHistory {
+            lines: LineReader::open(path)?,
+        })
+    }
+}
+
+impl Iterator for HashHistory {
+    type Item = Result<usize>;
+    fn next(&mut self) -> Option<Result<usize>> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(
+            line.trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        )
+    }
+}
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+// Reads the file CHUNK_SIZE bytes at a time and calls on_bytes with the
+// running total after every chunk, e.g. to drive a progress bar.
+pub fn read_with_progress<F: FnMut(usize)>(file: &str, mut on_bytes: F) -> Result<String> {
+    let mut file = File::open(file)?;
+    let mut bytes = Vec::new();
+    let mut chunk = [0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        bytes.extend_from_slice(&chunk[..n]);
+        on_bytes(bytes.len());
+    }
+    // A chunk boundary may fall in the middle of a char, so we can only
+    // check for valid UTF-8 once we have all the bytes.
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Files saved by some Windows tools start with a byte order mark,
+// which would otherwise end up glued to the front of the first line.
+pub fn read_whole_file_no_bom(file: &str) -> Result<String> {
+    let string = read_whole_file_good(file)?;
+    match string.strip_prefix('\u{FEFF}') {
+        Some(rest) => Ok(rest.to_string()),
+        None => Ok(string),
+    }
+}
+
+// Heuristic check before calling read_to_string on something that might
+// not be text at all. Only looks at the first CHUNK_SIZE bytes:
+// any null byte, or more than 30% control characters, means binary.
+// Bytes >= 0x80 are allowed since they show up in UTF-8 text.
+pub fn is_probably_binary(file: &str) -> Result<bool> {
+    let mut start = Vec::new();
+    File::open(file)?
+        .take(CHUNK_SIZE as u64)
+        .read_to_end(&mut start)?;
+    if start.contains(&0) {
+        return Ok(true);
+    }
+    let control = start
+        .iter()
+        .filter(|&&b| b < 0x20 && !b"\t\n\r\x0c".contains(&b))
+        .count();
+    Ok(control * 10 > start.len() * 3)
+}
+
+// Longest read_with_backoff will ever wait between two tries
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// base * 2^attempt, capped at MAX_BACKOFF. Both the power (from attempt
+// 32 on) and the multiplication can overflow, which would panic, so they
+// are checked and an overflow just means the cap.
+fn backoff_delay(base: Duration, attempt: usize) -> Duration {
+    u32::try_from(attempt)
+        .ok()
+        .and_then(|attempt| 2u32.checked_pow(attempt))
+        .and_then(|factor| base.checked_mul(factor))
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+// Retry a read that might fail transiently (e.g. a flaky network drive),
+// waiting base, 2 * base, 4 * base, ... (at most MAX_BACKOFF) between
+// tries. NotFound is not going to fix itself, so that is returned right
+// away. At least one attempt is always made.
+pub fn read_with_backoff(file: &str, attempts: usize, base: Duration) -> Result<String> {
+    let mut attempt = 0;
+    loop {
+        match read_whole_file_good(file) {
+            Ok(string) => return Ok(string),
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound || attempt + 1 >= attempts {
+                    return Err(e);
+                }
+            }
+        }
+        thread::sleep(backoff_delay(base, attempt));
+        attempt += 1;
+    }
+}
+
+// The last n lines of a file, reading backwards from the end in chunks so
+// a huge file doesn't have to be loaded just to see its tail.
+pub fn tail_lines(file: &str, n: usize) -> Result<Vec<String>> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let mut file = File::open(file)?;
+    let mut pos = file.seek(SeekFrom::End(0))?;
+    // Chunks in the order they were read, so the end of the file first.
+    // Only the newlines in each new chunk are counted, and the chunks are
+    // only glued together once at the end, so every byte is handled once.
+    let mut chunks: Vec<Vec<u8>> = Vec::new();
+    let mut newlines = 0;
+    // Stop once we have n + 1 newlines: the extra one covers both a
+    // trailing newline and the (possibly partial) line we start in.
+    while pos > 0 && newlines <= n {
+        let size = CHUNK_SIZE.min(pos as usize);
+        pos -= size as u64;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut chunk = vec![0; size];
+        file.read_exact(&mut chunk)?;
+        newlines += chunk.iter().filter(|&&b| b == b'\n').count();
+        chunks.push(chunk);
+    }
+    chunks.reverse();
+    let mut buf = chunks.concat();
+    // Unless we got all the way back to the start, the first line in buf
+    // is only partial (it might even begin in the middle of a char).
+    let start = if pos > 0 {
+        buf.iter()
+            .position(|&b| b == b'\n')
+            .map_or(buf.len(), |i| i + 1)
+    } else {
+        0
+    };
+    let text = String::from_utf8(buf.split_off(start))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let lines: Vec<&str> = text.lines().collect();
+    let skip = lines.len().saturating_sub(n);
+    Ok(lines[skip..].iter().map(|line| line.to_string()).collect())
+}
+
+// Just the first line, without its line ending.
+// Stops reading there, so this is cheap even on a huge file.
+// An empty file has no first line at all: Ok(None).
+pub fn read_first_line(file: &str) -> Result<Option<String>> {
+    let reader = BufReader::new(File::open(file)?);
+    reader.lines().next().transpose()
+}
+
+// Time the three ways of reading a whole file above on the same file:
+// read_whole_file, read_whole_file_decent and read_whole_file_good.
+// read_whole_file panics where the others return an Err, so it only runs
+// once read_whole_file_decent has read the file without an error.
+// The outputs are checked against each other, so a reader that is fast
+// because it is wrong doesn't go unnoticed: read_whole_file and
+// read_whole_file_decent drop the line endings, so they are compared with
+// read_whole_file_good's lines joined back together. Any read error, or a
+// disagreement, is an Err.
+pub fn bench_readers(file: &str) -> Result<Vec<(&'static str, Duration)>> {
+    fn time<T, F: FnOnce() -> Result<T>>(f: F) -> Result<(T, Duration)> {
+        let start = Instant::now();
+        let output = f()?;
+        Ok((output, start.elapsed()))
+    }
+    let (decent, decent_time) = time(|| read_whole_file_decent(file))?;
+    let (whole, whole_time) = time(|| Ok(read_whole_file(file)))?;
+    let (good, good_time) = time(|| read_whole_file_good(file))?;
+    let joined: String = good.lines().collect();
+    if whole != joined || decent != joined {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("file readers disagree on the contents of {}", file),
+        ));
+    }
+    Ok(vec![
+        ("read_whole_file", whole_time),
+        ("read_whole_file_decent", decent_time),
+        ("read_whole_file_good", good_time),
+    ])
+}
+
+// The longest string that every line of the file starts with.
+// Compared char by char, so the prefix never ends halfway through a char.
+pub fn common_line_prefix(file: &str) -> Result<String> {
+    let mut prefix: Option<Vec<char>> = None;
+    for line in LineReader::open(file)? {
+        let line = line?;
+        prefix = Some(match prefix {
+            None => line.chars().collect(),
+            Some(prefix) => prefix
+                .into_iter()
+                .zip(line.chars())
+                .take_while(|&(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    Ok(prefix.unwrap_or_default().into_iter().collect())
+}
+
+// "You can't reuse any of your last n passwords": only the last n hashes
+// of the history file count, older ones are allowed again.
+pub fn reused_within(path: &str, hash: usize, n: usize) -> Result<bool> {
+    for line in tail_lines(path, n)? {
+        let past: usize = line
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if past == hash {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Adler-32 checksum of the file, read CHUNK_SIZE bytes at a time so the
+// whole file never has to be in memory. Not cryptographic: just a cheap
+// way to notice that e.g. the history file changed between runs.
+pub fn file_checksum(file: &str) -> Result<u64> {
+    const MOD_ADLER: u64 = 65521;
+    let mut file = File::open(file)?;
+    let mut chunk = [0; CHUNK_SIZE];
+    let (mut a, mut b) = (1, 0);
+    loop {
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        for &byte in &chunk[..n] {
+            a = (a + u64::from(byte)) % MOD_ADLER;
+            b = (b + a) % MOD_ADLER;
+        }
+    }
+    Ok((b << 16) | a)
+}
+
+// Non-overlapping occurrences of needle in the file ("aa" occurs twice in
+// "aaaa", not three times). str::matches works on whole chars, so a
+// multi-byte needle is never matched halfway. An empty needle would match
+// everywhere, so that is rejected as InvalidInput instead.
+pub fn count_substring(file: &str, needle: &str) -> Result<usize> {
+    if needle.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot count an empty needle",
+        ));
+    }
+    Ok(read_whole_file_good(file)?.matches(needle).count())
+}
+
+// How often each word occurs in the file. Words are split on whitespace
+// and lowercased, but punctuation is kept: "end." and "end" are
+// different words.
+pub fn word_frequencies(file: &str) -> Result<HashMap<String, usize>> {
+    let mut counts = HashMap::new();
+    for line in LineReader::open(file)? {
+        for word in line?.split_whitespace() {
+            *counts.entry(word.to_lowercase()).or_insert(0) += 1;
+        }
+    }
+    Ok(counts)
+}
+
+// Each line paired with its line number, counting from 1 like an editor
+// does, for error messages that point at the offending line.
+pub fn enumerate_lines(file: &str) -> Result<impl Iterator<Item = Result<(usize, String)>>> {
+    Ok(LineReader::open(file)?
+        .enumerate()
+        .map(|(i, line)| line.map(|line| (i + 1, line))))
+}
+
+// Whether the file is valid UTF-8. Invalid UTF-8 is an answer here
+// (Ok(false)), not an error; only failing to read the file is an Err.
+pub fn is_valid_utf8(file: &str) -> Result<bool> {
+    let bytes = fs::read(file)?;
+    Ok(str::from_utf8(&bytes).is_ok())
+}
+
+// Give up on a read that takes longer than timeout (e.g. a hung network
+// filesystem). The read runs on its own thread; on timeout that thread is
+// left to finish (or hang) in the background, since Rust has no way to
+// kill a thread, and its result is thrown away.
+pub fn read_whole_file_timeout(file: &str, timeout: Duration) -> Result<String> {
+    let (tx, rx) = mpsc::channel();
+    let path = file.to_string();
+    thread::spawn(move || {
+        // The receiver may already have given up, so ignore send errors
+        let _ = tx.send(read_whole_file_good(&path));
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("reading {} took longer than {:?}", file, timeout),
+        )),
+    }
+}
+
+// Paragraphs are separated by one or more blank (or all-whitespace)
+// lines. Lines within a paragraph are joined back with '\n', each with its
+// trailing whitespace trimmed.
+pub fn read_paragraphs(file: &str) -> Result<Vec<String>> {
+    let mut paragraphs = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    for line in LineReader::open(file)? {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(current.join("\n"));
+                current.clear();
+            }
+        } else {
+            current.push(line.to_string());
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current.join("\n"));
+    }
+    Ok(paragraphs)
+}
+
+// The file as a Vec of chars (Unicode scalar values), for processing one
+// character at a time. Note a single emoji can still be several chars,
+// e.g. "❤️" is a heart followed by a variation selector.
+pub fn file_to_chars(file: &str) -> Result<Vec<char>> {
+    Ok(read_whole_file_good(file)?.chars().collect())
+}
+
+// Stream input to output one line at a time, passing each line through f.
+// Only one line is in memory at once. Returns how many lines were written.
+pub fn transform_file<F: Fn(&str) -> String>(input: &str, output: &str, f: F) -> Result<usize> {
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut count = 0;
+    for line in LineReader::open(input)? {
+        writeln!(writer, "{}", f(&line?))?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// Lint-style check that the file ends with '\n'. Only the last byte is
+// read. An empty file has no last byte, so it doesn't.
+pub fn ends_with_newline(file: &str) -> Result<bool> {
+    let mut file = File::open(file)?;
+    if file.seek(SeekFrom::End(0))? == 0 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0; 1];
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    // UTF-8 starting with a byte order mark, see read_whole_file_no_bom
+    Utf8Bom,
+    Latin1,
+    Unknown,
+}
+
+// Best guess at how a text file is encoded:
+// - a UTF-8 byte order mark means Utf8Bom
+// - otherwise valid UTF-8 is Utf8
+// - otherwise, if there are no control characters (apart from the usual
+//   whitespace), every byte is some Latin-1 character, so guess Latin1
+// - anything else (e.g. binary data) is Unknown
+pub fn detect_encoding(file: &str) -> Result<Encoding> {
+    let bytes = fs::read(file)?;
+    if bytes.starts_with(b"\xEF\xBB\xBF") {
+        return Ok(Encoding::Utf8Bom);
+    }
+    if str::from_utf8(&bytes).is_ok() {
+        return Ok(Encoding::Utf8);
+    }
+    let is_text = |b: u8| (b >= 0x20 && b != 0x7F) || b"\t\n\r\x0c".contains(&b);
+    if bytes.iter().all(|&b| is_text(b)) {
+        Ok(Encoding::Latin1)
+    } else {
+        Ok(Encoding::Unknown)
+    }
+}
+
+// histogram[i] = number of lines that are i chars long (newline excluded).
+// The vector is as long as the longest line + 1; an empty file gives [].
+pub fn line_length_histogram(file: &str) -> Result<Vec<usize>> {
+    let mut histogram = Vec::new();
+    for line in BufReader::new(File::open(file)?).lines() {
+        let len = line?.chars().count();
+        if len >= histogram.len() {
+            histogram.resize(len + 1, 0);
+        }
+        histogram[len] += 1;
+    }
+    Ok(histogram)
+}
+
+// All the distinct lines of a file, sorted, e.g. to clean up a wordlist
+pub fn read_unique_sorted_lines(file: &str) -> Result<BTreeSet<String>> {
+    BufReader::new(File::open(file)?).lines().collect()
+}
+
+// First line for which pred is true, or None. Lines are read one at a
+// time and we stop as soon as one matches, so the rest of the file is
+// never read.
+pub fn find_line<F: Fn(&str) -> bool>(file: &str, pred: F) -> Result<Option<String>> {
+    for line in BufReader::new(File::open(file)?).lines() {
+        let line = line?;
+        if pred(&line) {
+            return Ok(Some(line));
+        }
+    }
+    Ok(None)
+}
+
+// (line number, line in a, line in b) for every line that differs, with
+// line numbers starting at 1. Only meant for files with the same number
+// of lines (e.g. two versions of a hash file); anything else is an
+// InvalidData error.
+pub fn line_diff(a: &str, b: &str) -> Result<Vec<(usize, String, String)>> {
+    let a: Vec<String> = BufReader::new(File::open(a)?)
+        .lines()
+        .collect::<Result<_>>()?;
+    let b: Vec<String> = BufReader::new(File::open(b)?)
+        .lines()
+        .collect::<Result<_>>()?;
+    if a.len() != b.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line counts differ: {} vs {}", a.len(), b.len()),
+        ));
+    }
+    Ok(a.into_iter()
+        .zip(b)
+        .enumerate()
+        .filter(|&(_, (ref a, ref b))| a != b)
+        .map(|(i, (a, b))| (i + 1, a, b))
+        .collect())
+}
+
 // Similarly:
 // pub fn file_to_vec(filepath: &str) -> Result<Vec<usize>, String> {
 
@@ -146,5 +638,693 @@ pub fn f() -> Box<dyn Error> {
 
 pub type GenResult<T> = ::std::result::Result<T, Box<dyn Error>>;
 
+// Revisiting file_to_vec from panic.rs: instead of two .unwrap()s, both the
+// io::Error and the ParseIntError are propagated with ?.
+// They have different types, which is exactly what GenResult is for.
+pub fn file_to_vec(filepath: &str) -> GenResult<Vec<usize>> {
+    let file = File::open(filepath)?;
+    let reader = BufReader::new(file);
+    let mut hashes = Vec::new();
+    for line in reader.lines() {
+        hashes.push(line?.parse()?);
+    }
+    Ok(hashes)
+}
+
+// Whether two hash files contain the same hashes, ignoring order and
+// duplicates.
+pub fn hash_files_equal(a: &str, b: &str) -> GenResult<bool> {
+    let a: HashSet<usize> = file_to_vec(a)?.into_iter().collect();
+    let b: HashSet<usize> = file_to_vec(b)?.into_iter().collect();
+    Ok(a == b)
+}
+
+// Deduplicate and sort a hash file, rewriting it in place.
+// Returns how many unique hashes are left.
+pub fn compact_hash_file(path: &str) -> GenResult<usize> {
+    let mut hashes = file_to_vec(path)?;
+    hashes.sort_unstable();
+    hashes.dedup();
+    let mut contents = String::new();
+    for hash in &hashes {
+        contents.push_str(&format!("{}\n", hash));
+    }
+    fs::write(path, contents)?;
+    Ok(hashes.len())
+}
+
+// Simple config files: key=value per line, whitespace around either side
+// is trimmed. Blank lines and # comments are skipped.
+pub fn read_kv(file: &str) -> GenResult<Vec<(String, String)>> {
+    let reader = BufReader::new(File::open(file)?);
+    let mut pairs = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.find('=') {
+            Some(eq) => pairs.push((
+                line[..eq].trim().to_string(),
+                line[eq + 1..].trim().to_string(),
+            )),
+            None => return Err(format!("line {}: expected key=value", i + 1).into()),
+        }
+    }
+    Ok(pairs)
+}
+
+// The most recently appended hash, i.e. the last line of the history file,
+// or None if the file is empty. Uses tail_lines, so only the end of the
+// file is read.
+pub fn last_hash(path: &str) -> GenResult<Option<usize>> {
+    match tail_lines(path, 1)?.pop() {
+        Some(line) => Ok(Some(line.trim().parse()?)),
+        None => Ok(None),
+    }
+}
+
+// Merge several user:hash history files into out, dropping duplicate
+// (user, hash) pairs. The output is sorted by user and then hash, so
+// merging the same inputs always gives the same file.
+// Returns the number of unique pairs written.
+pub fn merge_user_histories(paths: &[&str], out: &str) -> GenResult<usize> {
+    let mut merged: BTreeMap<String, BTreeSet<usize>> = BTreeMap::new();
+    for path in paths {
+        for (i, line) in LineReader::open(path)?.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (user, hash) = line
+                .rsplit_once(':')
+                .ok_or_else(|| format!("{} line {}: expected user:hash", path, i + 1))?;
+            let hash = hash
+                .trim()
+                .parse()
+                .map_err(|e| format!("{} line {}: bad hash: {}", path, i + 1, e))?;
+            merged.entry(user.to_string()).or_default().insert(hash);
+        }
+    }
+    let mut contents = String::new();
+    let mut count = 0;
+    for (user, hashes) in &merged {
+        for hash in hashes {
+            contents.push_str(&format!("{}:{}\n", user, hash));
+            count += 1;
+        }
+    }
+    fs::write(out, contents)?;
+    Ok(count)
+}
+
+// (min, max, mean) of a file with one floating-point number per line.
+// Blank lines are skipped. There is no sensible answer for a file with no
+// numbers in it, so that is an error too.
+pub fn read_stats(file: &str) -> GenResult<(f64, f64, f64)> {
+    let mut min = f64::INFINITY;
+    let mut max = f64::NEG_INFINITY;
+    let mut sum = 0.0;
+    let mut count = 0;
+    for line in enumerate_lines(file)? {
+        let (number, line) = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let x: f64 = line
+            .parse()
+            .map_err(|e| format!("line {}: {:?} is not a number: {}", number, line, e))?;
+        min = min.min(x);
+        max = max.max(x);
+        sum += x;
+        count += 1;
+    }
+    if count == 0 {
+        return Err(format!("{} has no measurements", file).into());
+    }
+    Ok((min, max, sum / f64::from(count)))
+}
+
+// One entry point for history files however they are stored: "x.gz" is
+// gunzipped, "x.bz2" bunzipped and anything else read as plain text.
+// The compressed formats need the "compression" feature.
+pub fn read_history_any(path: &str) -> GenResult<Vec<usize>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = if path.ends_with(".gz") {
+        gz_reader(file)?
+    } else if path.ends_with(".bz2") {
+        bz2_reader(file)?
+    } else {
+        Box::new(file)
+    };
+    let mut hashes = Vec::new();
+    for line in BufReader::new(reader).lines() {
+        hashes.push(line?.trim().parse()?);
+    }
+    Ok(hashes)
+}
+
+#[cfg(feature = "compression")]
+fn gz_reader(file: File) -> GenResult<Box<dyn Read>> {
+    Ok(Box::new(flate2::read::GzDecoder::new(file)))
+}
+
+#[cfg(not(feature = "compression"))]
+fn gz_reader(_file: File) -> GenResult<Box<dyn Read>> {
+    Err("reading .gz files needs the compression feature".into())
+}
+
+#[cfg(feature = "compression")]
+fn bz2_reader(file: File) -> GenResult<Box<dyn Read>> {
+    Ok(Box::new(bzip2::read::BzDecoder::new(file)))
+}
+
+#[cfg(not(feature = "compression"))]
+fn bz2_reader(_file: File) -> GenResult<Box<dyn Read>> {
+    Err("reading .bz2 files needs the compression feature".into())
+}
+
 // Dealing with errors that "can't" happen
 // Call unwrap or expect on your Result value
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Write contents to a file called name in dir and return its path
+    fn fixture(dir: &TempDir, name: &str, contents: &[u8]) -> String {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn line_reader_yields_each_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "lines", b"one\ntwo\n\nfour");
+        let lines: Vec<String> = LineReader::open(&path)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(lines, ["one", "two", "", "four"]);
+        assert!(LineReader::open(&format!("{}.missing", path)).is_err());
+    }
+
+    #[test]
+    fn read_with_progress_reports_file_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = "0123456789\n".repeat(2000);
+        let path = fixture(&dir, "big", contents.as_bytes());
+        let mut reported = Vec::new();
+        let read = read_with_progress(&path, |n| reported.push(n)).unwrap();
+        assert_eq!(read, contents);
+        assert!(reported.len() > 1);
+        assert_eq!(reported.last(), Some(&contents.len()));
+        assert!(reported.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn read_whole_file_no_bom_strips_bom() {
+        let dir = tempfile::tempdir().unwrap();
+        let with_bom = fixture(&dir, "bom", "\u{FEFF}user:pass\n".as_bytes());
+        assert_eq!(read_whole_file_no_bom(&with_bom).unwrap(), "user:pass\n");
+        let plain = fixture(&dir, "plain", b"user:pass\n");
+        assert_eq!(read_whole_file_no_bom(&plain).unwrap(), "user:pass\n");
+    }
+
+    #[test]
+    fn is_probably_binary_spots_null_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = fixture(
+            &dir,
+            "text",
+            "caleb:cis198rust!:20210225\n\tindented ❤\n".as_bytes(),
+        );
+        assert!(!is_probably_binary(&text).unwrap());
+        let nulls = fixture(&dir, "nulls", b"ELF\x00\x01\x02 mostly text");
+        assert!(is_probably_binary(&nulls).unwrap());
+        let control = fixture(&dir, "control", b"\x01\x02\x03\x04ab");
+        assert!(is_probably_binary(&control).unwrap());
+    }
+
+    #[test]
+    fn hash_files_equal_ignores_order_and_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = fixture(&dir, "a", b"1\n2\n3\n");
+        let b = fixture(&dir, "b", b"3\n1\n2\n1\n");
+        let c = fixture(&dir, "c", b"1\n2\n4\n");
+        assert!(hash_files_equal(&a, &b).unwrap());
+        assert!(!hash_files_equal(&a, &c).unwrap());
+    }
+
+    #[test]
+    fn compact_hash_file_sorts_and_dedups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "history", b"30\n10\n20\n10\n30\n");
+        assert_eq!(compact_hash_file(&path).unwrap(), 3);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "10\n20\n30\n");
+    }
+
+    #[test]
+    fn read_first_line_stops_at_newline() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = fixture(&dir, "lines", b"first\r\nsecond\nthird\n");
+        assert_eq!(read_first_line(&lines).unwrap(), Some("first".to_string()));
+        let empty = fixture(&dir, "empty", b"");
+        assert_eq!(read_first_line(&empty).unwrap(), None);
+    }
+
+    #[test]
+    fn read_kv_parses_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(
+            &dir,
+            "config",
+            b"# settings\nmin_len = 8\n\nname=caleb = admin\n",
+        );
+        assert_eq!(
+            read_kv(&path).unwrap(),
+            [
+                ("min_len".to_string(), "8".to_string()),
+                ("name".to_string(), "caleb = admin".to_string()),
+            ]
+        );
+        let bad = fixture(&dir, "bad", b"a=1\nno equals sign\n");
+        let err = read_kv(&bad).unwrap_err().to_string();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn common_line_prefix_of_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "users", "user:caleb\nuser:cara\nuser:cé\n".as_bytes());
+        assert_eq!(common_line_prefix(&path).unwrap(), "user:c");
+        let accents = fixture(&dir, "accents", "héllo\nhéllp\n".as_bytes());
+        assert_eq!(common_line_prefix(&accents).unwrap(), "héll");
+        let empty = fixture(&dir, "empty", b"");
+        assert_eq!(common_line_prefix(&empty).unwrap(), "");
+    }
+
+    #[test]
+    fn reused_within_only_checks_last_n() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "history", b"1\n2\n3\n4\n5\n6\n");
+        assert!(!reused_within(&path, 1, 5).unwrap());
+        assert!(reused_within(&path, 2, 5).unwrap());
+        assert!(reused_within(&path, 6, 1).unwrap());
+        assert!(!reused_within(&path, 6, 0).unwrap());
+        assert!(reused_within(&path, 1, 100).unwrap());
+    }
+
+    #[test]
+    fn file_checksum_detects_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = fixture(&dir, "a", b"Wikipedia");
+        let b = fixture(&dir, "b", b"Wikipedia");
+        let c = fixture(&dir, "c", b"Wikipedib");
+        // The usual Adler-32 test vector
+        assert_eq!(file_checksum(&a).unwrap(), 0x11E6_0398);
+        assert_eq!(file_checksum(&a).unwrap(), file_checksum(&b).unwrap());
+        assert_ne!(file_checksum(&a).unwrap(), file_checksum(&c).unwrap());
+        // Spread over several chunks, same as all at once
+        let bytes: Vec<u8> = (0..3 * CHUNK_SIZE + 1).map(|i| i as u8).collect();
+        let (mut x, mut y) = (1u64, 0u64);
+        for &byte in &bytes {
+            x = (x + u64::from(byte)) % 65521;
+            y = (y + x) % 65521;
+        }
+        let big = fixture(&dir, "big", &bytes);
+        assert_eq!(file_checksum(&big).unwrap(), (y << 16) | x);
+    }
+
+    #[test]
+    fn count_substring_counts_non_overlapping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "text", "aaaa ❤❤❤ caleb\ncaleb".as_bytes());
+        assert_eq!(count_substring(&path, "aa").unwrap(), 2);
+        assert_eq!(count_substring(&path, "❤").unwrap(), 3);
+        assert_eq!(count_substring(&path, "caleb").unwrap(), 2);
+        assert_eq!(
+            count_substring(&path, "").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn word_frequencies_are_case_insensitive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "words", b"Rust is fun\nrust  RUST\tis\n");
+        let counts = word_frequencies(&path).unwrap();
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts["rust"], 3);
+        assert_eq!(counts["is"], 2);
+        assert_eq!(counts["fun"], 1);
+    }
+
+    #[test]
+    fn hash_history_is_lazy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "history", b"10\n 20 \ngarbage\n30\n");
+        let mut history = HashHistory::open(&path).unwrap();
+        assert_eq!(history.next().unwrap().unwrap(), 10);
+        assert_eq!(history.next().unwrap().unwrap(), 20);
+        // A bad line is an error for that line only
+        assert_eq!(
+            history.next().unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(history.next().unwrap().unwrap(), 30);
+        assert!(history.next().is_none());
+    }
+
+    #[test]
+    fn enumerate_lines_starts_at_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "lines", b"first\nsecond\n");
+        let lines: Vec<(usize, String)> = enumerate_lines(&path)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(lines, [(1, "first".to_string()), (2, "second".to_string())]);
+    }
+
+    #[test]
+    fn is_valid_utf8_answers_without_erroring() {
+        let dir = tempfile::tempdir().unwrap();
+        let valid = fixture(&dir, "valid", "héllo ❤\n".as_bytes());
+        assert!(is_valid_utf8(&valid).unwrap());
+        let invalid = fixture(&dir, "invalid", b"abc\xff\xfe");
+        assert!(!is_valid_utf8(&invalid).unwrap());
+        assert!(is_valid_utf8(&format!("{}.missing", valid)).is_err());
+    }
+
+    #[test]
+    fn read_whole_file_timeout_returns_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "small", b"quick\n");
+        let timeout = Duration::from_secs(5);
+        assert_eq!(read_whole_file_timeout(&path, timeout).unwrap(), "quick\n");
+        // Errors from the read itself come through unchanged
+        let missing = format!("{}.missing", path);
+        assert_eq!(
+            read_whole_file_timeout(&missing, timeout)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn read_paragraphs_splits_on_blank_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(
+            &dir,
+            "text",
+            b"\nFirst line  \nstill first\n\n   \n\nSecond\t\n",
+        );
+        assert_eq!(
+            read_paragraphs(&path).unwrap(),
+            ["First line\nstill first", "Second"]
+        );
+    }
+
+    #[test]
+    fn last_hash_is_the_last_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "history", b"10\n20\n30\n");
+        assert_eq!(last_hash(&path).unwrap(), Some(30));
+        let no_newline = fixture(&dir, "no_newline", b"10\n20");
+        assert_eq!(last_hash(&no_newline).unwrap(), Some(20));
+        let empty = fixture(&dir, "empty", b"");
+        assert_eq!(last_hash(&empty).unwrap(), None);
+        // Much longer than one tail_lines chunk
+        let mut long: Vec<u8> = "1\n".repeat(10_000).into_bytes();
+        long.extend_from_slice(b"42\n");
+        let long = fixture(&dir, "long", &long);
+        assert_eq!(last_hash(&long).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn merge_user_histories_dedups_per_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = fixture(&dir, "a", b"caleb:3\nalice:1\n");
+        let b = fixture(&dir, "b", b"caleb:2\ncaleb:3\n\n");
+        let out = dir.path().join("merged").to_str().unwrap().to_string();
+        assert_eq!(merge_user_histories(&[&a, &b], &out).unwrap(), 3);
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "alice:1\ncaleb:2\ncaleb:3\n"
+        );
+        let bad = fixture(&dir, "bad", b"caleb:3\nno hash here\n");
+        let err = merge_user_histories(&[&bad], &out).unwrap_err().to_string();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn read_stats_of_measurements() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "data", b"1.5\n-2\n\n3.5\n");
+        assert_eq!(read_stats(&path).unwrap(), (-2.0, 3.5, 1.0));
+        let empty = fixture(&dir, "empty", b"\n\n");
+        assert!(read_stats(&empty).is_err());
+        let bad = fixture(&dir, "bad", b"1.0\nabc\n");
+        let err = read_stats(&bad).unwrap_err().to_string();
+        assert!(err.starts_with("line 2:"), "{}", err);
+    }
+
+    #[test]
+    fn file_to_chars_counts_chars_not_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "emoji", "a❤é🦀\n".as_bytes());
+        let chars = file_to_chars(&path).unwrap();
+        assert_eq!(chars, ['a', '❤', 'é', '🦀', '\n']);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 11);
+    }
+
+    #[test]
+    fn transform_file_applies_to_each_line() {
+        use panic::capitalize_firstchar;
+
+        let dir = tempfile::tempdir().unwrap();
+        let input = fixture(&dir, "input", "hello\nworld\néclair\n".as_bytes());
+        let output = dir.path().join("output").to_str().unwrap().to_string();
+        assert_eq!(
+            transform_file(&input, &output, capitalize_firstchar).unwrap(),
+            3
+        );
+        // capitalize_firstchar keeps only the (capitalized) first char
+        assert_eq!(fs::read_to_string(&output).unwrap(), "H\nW\nÉ\n");
+        let upper = dir.path().join("upper").to_str().unwrap().to_string();
+        transform_file(&input, &upper, |line| line.to_uppercase()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&upper).unwrap(),
+            "HELLO\nWORLD\nÉCLAIR\n"
+        );
+    }
+
+    #[test]
+    fn ends_with_newline_checks_last_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(ends_with_newline(&fixture(&dir, "with", b"1\n2\n")).unwrap());
+        assert!(!ends_with_newline(&fixture(&dir, "without", b"1\n2")).unwrap());
+        assert!(!ends_with_newline(&fixture(&dir, "empty", b"")).unwrap());
+        assert!(!ends_with_newline(&fixture(&dir, "cr", b"1\r")).unwrap());
+    }
+
+    #[test]
+    fn detect_encoding_guesses() {
+        let dir = tempfile::tempdir().unwrap();
+        let bom = fixture(&dir, "bom", "\u{FEFF}héllo\n".as_bytes());
+        assert_eq!(detect_encoding(&bom).unwrap(), Encoding::Utf8Bom);
+        let utf8 = fixture(&dir, "utf8", "héllo\n".as_bytes());
+        assert_eq!(detect_encoding(&utf8).unwrap(), Encoding::Utf8);
+        // "héllo" in Latin-1: é is the single byte 0xE9
+        let latin1 = fixture(&dir, "latin1", b"h\xe9llo\n");
+        assert_eq!(detect_encoding(&latin1).unwrap(), Encoding::Latin1);
+        let binary = fixture(&dir, "binary", b"\xff\x00\x01");
+        assert_eq!(detect_encoding(&binary).unwrap(), Encoding::Unknown);
+    }
+
+    #[test]
+    fn line_length_histogram_by_chars() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "lines", "ab\n\nabc\né❤\nxyz\n".as_bytes());
+        assert_eq!(line_length_histogram(&path).unwrap(), [1, 0, 2, 2]);
+        let empty = fixture(&dir, "empty", b"");
+        assert!(line_length_histogram(&empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_unique_sorted_lines_dedups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "words", b"pear\napple\npear\nbanana\napple\n");
+        let lines: Vec<String> = read_unique_sorted_lines(&path)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(lines, ["apple", "banana", "pear"]);
+    }
+
+    #[test]
+    fn read_history_any_reads_plain_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = fixture(&dir, "history", b"1\n22\n333\n");
+        assert_eq!(read_history_any(&plain).unwrap(), [1, 22, 333]);
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn read_history_any_needs_compression_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let gz = fixture(&dir, "history.gz", b"");
+        let err = read_history_any(&gz).unwrap_err().to_string();
+        assert!(err.contains("compression feature"), "{}", err);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn read_history_any_reads_compressed_files_the_same() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = b"1\n22\n333\n";
+        let plain = fixture(&dir, "history", contents);
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(contents).unwrap();
+        let gz = fixture(&dir, "history.gz", &gz.finish().unwrap());
+
+        let mut bz2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz2.write_all(contents).unwrap();
+        let bz2 = fixture(&dir, "history.bz2", &bz2.finish().unwrap());
+
+        let expected = read_history_any(&plain).unwrap();
+        assert_eq!(read_history_any(&gz).unwrap(), expected);
+        assert_eq!(read_history_any(&bz2).unwrap(), expected);
+    }
+
+    #[test]
+    fn find_line_returns_first_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "log", b"start\nerror: disk\nok\nerror: net\n");
+        assert_eq!(
+            find_line(&path, |line| line.contains("error")).unwrap(),
+            Some("error: disk".to_string())
+        );
+        assert_eq!(
+            find_line(&path, |line| line.contains("panic")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn line_diff_reports_changed_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = fixture(&dir, "a", b"one\ntwo\nthree\n");
+        let b = fixture(&dir, "b", b"one\nTWO\nthree\n");
+        assert_eq!(
+            line_diff(&a, &b).unwrap(),
+            [(2, "two".to_string(), "TWO".to_string())]
+        );
+        assert!(line_diff(&a, &a).unwrap().is_empty());
+        let shorter = fixture(&dir, "shorter", b"one\n");
+        assert_eq!(
+            line_diff(&a, &shorter).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn read_with_backoff_recovers_from_transient_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        // Invalid UTF-8 fails with InvalidData (which is retried) until
+        // the "writer" below finishes the file
+        let path = fixture(&dir, "flaky.txt", b"\xff");
+        // Written elsewhere and renamed over it, so a read can never see
+        // a half-written file
+        let done = fixture(&dir, "done.txt", b"done");
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            fs::rename(done, writer_path).unwrap();
+        });
+        let result = read_with_backoff(&path, 10, Duration::from_millis(5));
+        writer.join().unwrap();
+        assert_eq!(result.unwrap(), "done");
+    }
+
+    #[test]
+    fn read_with_backoff_gives_up_on_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.txt");
+        let start = Instant::now();
+        let err = read_with_backoff(missing.to_str().unwrap(), 100, Duration::from_secs(1));
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_delay_saturates() {
+        let base = Duration::from_millis(10);
+        assert_eq!(backoff_delay(base, 0), base);
+        assert_eq!(backoff_delay(base, 3), base * 8);
+        assert_eq!(backoff_delay(base, 32), MAX_BACKOFF);
+        assert_eq!(backoff_delay(Duration::MAX, 1), MAX_BACKOFF);
+        assert_eq!(backoff_delay(base, usize::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn tail_lines_of_small_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "five.txt", b"one\ntwo\nthree\nfour\nfive\n");
+        assert_eq!(tail_lines(&path, 2).unwrap(), ["four", "five"]);
+        assert_eq!(tail_lines(&path, 10).unwrap().len(), 5);
+        assert!(tail_lines(&path, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tail_lines_across_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines: Vec<String> = (0..5000).map(|i| format!("line {}", i)).collect();
+        let mut contents = lines.join("\n");
+        // No trailing newline this time
+        let path = fixture(&dir, "long.txt", contents.as_bytes());
+        assert_eq!(tail_lines(&path, 3000).unwrap(), &lines[2000..]);
+        // One line much longer than a chunk
+        contents.push('\n');
+        contents.push_str(&"x".repeat(3 * CHUNK_SIZE));
+        let path = fixture(&dir, "wide.txt", contents.as_bytes());
+        let tail = tail_lines(&path, 2).unwrap();
+        assert_eq!(tail[0], "line 4999");
+        assert_eq!(tail[1].len(), 3 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn bench_readers_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = fixture(&dir, "bench.txt", "one\ntwo ❤\r\nthree\n".as_bytes());
+        let names: Vec<&str> = bench_readers(&path)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "read_whole_file",
+                "read_whole_file_decent",
+                "read_whole_file_good"
+            ]
+        );
+        assert_eq!(read_whole_file(&path), "onetwo ❤three");
+    }
+
+    #[test]
+    fn bench_readers_missing_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.txt");
+        let err = bench_readers(missing.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,191 @@
+/*
+    String helpers that work on chars rather than bytes.
+
+    Recall from capitalize_firstchar in panic.rs: indexing a String by
+    byte offset is not safe in general, because a single char can take
+    up to 4 bytes in UTF-8. Everything here goes through .chars() so we
+    never split a multi-byte char in half.
+*/
+
+#[cfg(feature = "unicode")]
+use unicode_general_category::get_general_category;
+#[cfg(feature = "unicode")]
+use unicode_normalization::UnicodeNormalization;
+#[cfg(feature = "unicode")]
+use unicode_width::UnicodeWidthStr;
+
+// Split s into n pieces whose char counts differ by at most one.
+// The first (len % n) pieces get the extra char.
+// n == 0 gives back an empty Vec rather than dividing by zero.
+pub fn split_into_n(s: &str, n: usize) -> Vec<String> {
+    if n == 0 {
+        return Vec::new();
+    }
+    let len = s.chars().count();
+    let base = len / n;
+    let extra = len % n;
+
+    let mut chars = s.chars();
+    (0..n)
+        .map(|i| {
+            let size = if i < extra { base + 1 } else { base };
+            chars.by_ref().take(size).collect()
+        })
+        .collect()
+}
+
+// Edit distance: the minimum number of single-char insertions, deletions,
+// or substitutions to turn a into b. Counts chars, not bytes.
+// Classic dynamic programming, keeping only one row at a time.
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Whether a and b use exactly the same letters, ignoring case.
+// Digits, punctuation, etc. are skipped, so "belac1!" is an anagram of
+// "caleb". Strings with no letters at all are never anagrams.
+pub fn is_anagram(a: &str, b: &str) -> bool {
+    fn sorted_letters(s: &str) -> Vec<char> {
+        let mut letters: Vec<char> = s
+            .chars()
+            .filter(|ch| ch.is_alphabetic())
+            .flat_map(|ch| ch.to_lowercase())
+            .collect();
+        letters.sort();
+        letters
+    }
+    let a = sorted_letters(a);
+    !a.is_empty() && a == sorted_letters(b)
+}
+
+// "é" can be one char (U+00E9) or two (e + U+0301 combining accent).
+// NFC normalization picks the precomposed form wherever one exists,
+// so both spellings become the same String.
+#[cfg(feature = "unicode")]
+pub fn normalize_nfc(s: &str) -> String {
+    s.nfc().collect()
+}
+
+// How many terminal columns s takes up, for lining up tables of
+// passwords / usernames. Not the same as chars().count(): CJK chars like
+// "漢" are 2 columns wide and combining marks like U+0301 take none.
+#[cfg(feature = "unicode")]
+pub fn display_width(s: &str) -> usize {
+    s.width()
+}
+
+// Each char of s with its code point and its Unicode general category,
+// by major class: "letter", "mark", "number", "punctuation", "symbol",
+// "separator" or "other" (control chars, unassigned code points, ...).
+// std can't tell e.g. "¿" (punctuation) from "❤" (symbol), so this needs
+// the category tables from the "unicode" feature.
+#[cfg(feature = "unicode")]
+pub fn unicode_report(s: &str) -> Vec<(char, u32, String)> {
+    s.chars()
+        .map(|ch| {
+            // The abbreviation's first letter is the major class, e.g. "Po"
+            let category = match get_general_category(ch).abbreviation().chars().next() {
+                Some('L') => "letter",
+                Some('M') => "mark",
+                Some('N') => "number",
+                Some('P') => "punctuation",
+                Some('S') => "symbol",
+                Some('Z') => "separator",
+                _ => "other",
+            };
+            (ch, ch as u32, category.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_into_n_balances_chunks() {
+        assert_eq!(split_into_n("abcdefghij", 3), ["abcd", "efg", "hij"]);
+        assert!(split_into_n("abc", 0).is_empty());
+        assert_eq!(split_into_n("ab", 4), ["a", "b", "", ""]);
+    }
+
+    #[test]
+    fn split_into_n_keeps_chars_whole() {
+        let s = "héllo wörld ❤";
+        let chunks = split_into_n(s, 3);
+        assert_eq!(chunks.concat(), s);
+        let sizes: Vec<usize> = chunks.iter().map(|c| c.chars().count()).collect();
+        assert_eq!(sizes, [5, 4, 4]);
+    }
+
+    #[test]
+    fn is_anagram_ignores_case_and_symbols() {
+        assert!(is_anagram("belac1!", "caleb"));
+        assert!(is_anagram("Listen", "silent"));
+        assert!(is_anagram("cable", "caleb"));
+        assert!(!is_anagram("cables", "caleb"));
+        assert!(!is_anagram("!!", "??"));
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn normalize_nfc_composes_accents() {
+        assert_eq!(normalize_nfc("e\u{301}"), "\u{e9}");
+        assert_eq!(normalize_nfc("\u{e9}"), "\u{e9}");
+    }
+
+    #[test]
+    fn levenshtein_counts_chars() {
+        assert_eq!(levenshtein("caleb", "caleb1"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("héllo", "hello"), 1);
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn display_width_of_wide_and_zero_width_chars() {
+        assert_eq!(display_width("中"), 2);
+        assert_eq!(display_width("\u{301}"), 0);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("ab中文"), 6);
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn unicode_report_categories() {
+        let categories: Vec<String> = unicode_report("a1!❤")
+            .into_iter()
+            .map(|(_, _, category)| category)
+            .collect();
+        assert_eq!(categories, ["letter", "number", "punctuation", "symbol"]);
+        assert_eq!(unicode_report("❤")[0].1, 0x2764);
+        // Non-ASCII punctuation and digits from other scripts
+        let categories: Vec<String> = unicode_report("¿—«٣ \u{301}")
+            .into_iter()
+            .map(|(_, _, category)| category)
+            .collect();
+        assert_eq!(
+            categories,
+            [
+                "punctuation",
+                "punctuation",
+                "punctuation",
+                "number",
+                "separator",
+                "mark"
+            ]
+        );
+    }
+}
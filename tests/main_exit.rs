@@ -0,0 +1,32 @@
+use std::process::{Command, Output};
+
+fn run_lecture5(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lecture5"))
+        .args(args)
+        .output()
+        .expect("could not run the lecture5 binary")
+}
+
+// When main returns Err, Rust prints "Error: {:?}" of it and exits with 1.
+// main's error Debug-prints as its message, so there are no quotes.
+#[test]
+fn bad_password_exits_with_friendly_error() {
+    let output = run_lecture5(&["123!"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains('"'), "{}", stderr);
+    assert_eq!(
+        stderr.trim_end(),
+        "Error: could not create a password for caleb: Password is too short"
+    );
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn good_password_exits_cleanly() {
+    let output = run_lecture5(&[]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("\"Hash\": "));
+}